@@ -1 +1,36 @@
-fn main() {}
+use rsmqtt::{MqttClient, QoS};
+use std::time::Duration;
+
+#[tokio::main]
+async fn main() {
+    let mut client = MqttClient::new();
+    client
+        .client_id("rsmqtt-example")
+        .keepalive(30)
+        .will("clients/rsmqtt-example", "offline", QoS::AtLeastOnce, false)
+        .reconnect(Duration::from_secs(1), Duration::from_secs(30));
+    let mut events = client.events().unwrap();
+    tokio::spawn(async move {
+        while let Some(event) = events.recv().await {
+            println!("event: {:?}", event);
+        }
+    });
+    let connack = client.connect("127.0.0.1:1883").await.unwrap();
+    println!("connected: {:?}", connack);
+
+    let reason_code = client.subscribe("hello/#", QoS::ExactlyOnce).await.unwrap();
+    println!("subscribed: {:?}", reason_code);
+
+    for qos in [QoS::AtMostOnce, QoS::AtLeastOnce, QoS::ExactlyOnce] {
+        client
+            .publish("hello/world", format!("{:?}", qos), qos, false)
+            .await
+            .unwrap();
+        if let Some(publish) = client.recv().await {
+            println!("received: {} {:?}", publish.topic_name, publish.payload);
+        }
+    }
+
+    client.unsubscribe("hello/#").await.unwrap();
+    client.disconnect().await.unwrap();
+}
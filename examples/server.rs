@@ -1,11 +1,11 @@
 use rsmqtt::{ConnAck, Connect, Error, MqttServer, Packet, PubAck, Publish};
 use tokio::signal;
 
-fn connect(c: Connect) -> Result<Packet, Error> {
+fn connect(c: &Connect) -> Result<Packet, Error> {
     println!("connect hook: {:?}", c);
     Ok(Packet::ConnAck(ConnAck::default()))
 }
-fn publish(p: Publish) -> Result<Packet, Error> {
+fn publish(p: &Publish) -> Result<Packet, Error> {
     println!("Publish hook: {:?}", p);
     Ok(Packet::PubAck(PubAck::default()))
 }
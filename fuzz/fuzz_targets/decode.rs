@@ -0,0 +1,16 @@
+#![no_main]
+
+// cargo fuzz run decode
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use rsmqtt::{MqttCodec, Version};
+use tokio_util::codec::Decoder;
+
+fuzz_target!(|data: &[u8]| {
+    for version in [Version::V31, Version::V311, Version::V5] {
+        let mut codec = MqttCodec::new(version, u32::MAX);
+        let mut buf = BytesMut::from(data);
+        while let Ok(Some(_)) = codec.decode(&mut buf) {}
+    }
+});
@@ -0,0 +1,736 @@
+use crate::*;
+use bytes::{Bytes, BytesMut};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::ErrorKind;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use tokio::task;
+use tokio::time::{sleep_until, timeout, Instant};
+use tokio_util::codec::Encoder;
+
+// Time allowed for the server to answer CONNECT
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Reply = oneshot::Sender<Result<Packet, Error>>;
+
+// Connection state changes, see MqttClient::events
+#[derive(Debug)]
+pub enum Event {
+    Connected(ConnAck),
+    // Connection lost, or a reconnect attempt failed
+    Disconnected(Error),
+    // Next connection attempt after the delay
+    Reconnecting(Duration),
+}
+
+pub struct MqttClient {
+    options: Connect,
+    // Backoff bounds, no reconnect when unset
+    reconnect: Option<(Duration, Duration)>,
+    tx: Option<UnboundedSender<(Packet, Reply)>>,
+    rx: Option<UnboundedReceiver<Publish>>,
+    events_tx: UnboundedSender<Event>,
+    events: Option<UnboundedReceiver<Event>>,
+}
+
+impl MqttClient {
+    pub fn new() -> Self {
+        let mut options = Connect::new();
+        options.clean_start = true;
+        options.keepalive = 60;
+        let (events_tx, events) = mpsc::unbounded_channel();
+        Self {
+            options,
+            reconnect: None,
+            tx: None,
+            rx: None,
+            events_tx,
+            events: Some(events),
+        }
+    }
+
+    pub fn version(&mut self, version: Version) -> &mut Self {
+        self.options.protocol_version = version;
+        self
+    }
+    pub fn client_id(&mut self, client_id: &str) -> &mut Self {
+        self.options.client_id = client_id.to_owned();
+        self
+    }
+    pub fn keepalive(&mut self, keepalive: u16) -> &mut Self {
+        self.options.keepalive = keepalive;
+        self
+    }
+    pub fn clean_start(&mut self, clean_start: bool) -> &mut Self {
+        self.options.clean_start = clean_start;
+        self
+    }
+    pub fn credentials(&mut self, username: &str, password: impl Into<Bytes>) -> &mut Self {
+        self.options.username_flag = true;
+        self.options.username = username.to_owned();
+        self.options.password_flag = true;
+        self.options.password = password.into();
+        self
+    }
+    pub fn will(
+        &mut self,
+        topic: &str,
+        payload: impl Into<Bytes>,
+        qos: QoS,
+        retain: bool,
+    ) -> &mut Self {
+        self.options.will_flag = true;
+        self.options.will_topic = topic.to_owned();
+        self.options.will_payload = payload.into();
+        self.options.will_qos = qos;
+        self.options.will_retain = retain;
+        self
+    }
+    pub fn will_properties(&mut self, properties: WillProperties) -> &mut Self {
+        self.options.will_properties = Some(properties);
+        self
+    }
+    pub fn properties(&mut self, properties: ConnectProperties) -> &mut Self {
+        self.options.properties = Some(properties);
+        self
+    }
+
+    // Reconnects after a lost connection, the delay doubles from min up to max
+    pub fn reconnect(&mut self, min: Duration, max: Duration) -> &mut Self {
+        self.reconnect = Some((min, max.max(min)));
+        self
+    }
+
+    // Connection state changes, can be taken once
+    pub fn events(&mut self) -> Option<UnboundedReceiver<Event>> {
+        self.events.take()
+    }
+
+    pub async fn connect(&mut self, addr: &str) -> Result<ConnAck, Error> {
+        let stream = TcpStream::connect(addr).await?;
+        let events = self.events_tx.clone();
+        let mut conn = Connection::new(Box::new(stream), addr, self.options.clone(), events);
+        conn.reconnect = self.reconnect;
+        let connack = conn.handshake().await?;
+        let _ = self.events_tx.send(Event::Connected(connack.clone()));
+
+        let (tx, requests) = mpsc::unbounded_channel();
+        let (messages, rx) = mpsc::unbounded_channel();
+        task::spawn(conn.run(requests, messages));
+        self.tx = Some(tx);
+        self.rx = Some(rx);
+        Ok(connack)
+    }
+
+    pub async fn publish(
+        &self,
+        topic: &str,
+        payload: impl Into<Bytes>,
+        qos: QoS,
+        retain: bool,
+    ) -> Result<(), Error> {
+        let mut publish = Publish::new();
+        publish.topic_name = topic.to_owned();
+        publish.payload = payload.into();
+        publish.qos = qos;
+        publish.retain = retain;
+        self.publish_with(publish).await
+    }
+
+    // Resolves once the QoS flow completes, PUBACK for QoS 1 and PUBCOMP for QoS 2
+    pub async fn publish_with(&self, publish: Publish) -> Result<(), Error> {
+        let reason_code = match self.call(Packet::Publish(publish)).await? {
+            Packet::PubAck(puback) => puback.reason_code,
+            Packet::PubRec(pubrec) => pubrec.reason_code,
+            Packet::PubComp(pubcomp) => pubcomp.reason_code,
+            _ => ReasonCode::Success,
+        };
+        if reason_code >= ReasonCode::UnspecifiedError {
+            return Err(Error::Rejected(reason_code));
+        }
+        Ok(())
+    }
+
+    pub async fn subscribe(&self, topic: &str, qos: QoS) -> Result<ReasonCode, Error> {
+        let mut subscribe = Subscribe::new();
+        subscribe.payload.push(Subscription {
+            topic: topic.to_owned(),
+            qos,
+            ..Default::default()
+        });
+        let suback = self.subscribe_with(subscribe).await?;
+        let reason_code = suback.payload.first().copied();
+        Ok(reason_code.unwrap_or(ReasonCode::UnspecifiedError))
+    }
+
+    pub async fn subscribe_with(&self, subscribe: Subscribe) -> Result<SubAck, Error> {
+        match self.call(Packet::Subscribe(subscribe)).await? {
+            Packet::SubAck(suback) => Ok(suback),
+            _ => Err(Error::ConnectionClosed),
+        }
+    }
+
+    pub async fn unsubscribe(&self, topic: &str) -> Result<ReasonCode, Error> {
+        let mut unsubscribe = Unsubscribe::new();
+        unsubscribe.payload.push(topic.to_owned());
+        let unsuback = self.unsubscribe_with(unsubscribe).await?;
+        // v3 UNSUBACK has no reason codes
+        let reason_code = unsuback.payload.first().copied();
+        Ok(reason_code.unwrap_or(ReasonCode::Success))
+    }
+
+    pub async fn unsubscribe_with(&self, unsubscribe: Unsubscribe) -> Result<UnsubAck, Error> {
+        match self.call(Packet::Unsubscribe(unsubscribe)).await? {
+            Packet::UnsubAck(unsuback) => Ok(unsuback),
+            _ => Err(Error::ConnectionClosed),
+        }
+    }
+
+    // Next message from the server, None once the connection is gone
+    pub async fn recv(&mut self) -> Option<Publish> {
+        self.rx.as_mut()?.recv().await
+    }
+
+    pub async fn disconnect(&mut self) -> Result<(), Error> {
+        self.call(Packet::Disconnect(Disconnect::new())).await?;
+        self.tx = None;
+        Ok(())
+    }
+
+    async fn call(&self, packet: Packet) -> Result<Packet, Error> {
+        let tx = self.tx.as_ref().ok_or(Error::ConnectionClosed)?;
+        let (reply, rx) = oneshot::channel();
+        tx.send((packet, reply))
+            .map_err(|_| Error::ConnectionClosed)?;
+        rx.await.map_err(|_| Error::ConnectionClosed)?
+    }
+}
+impl Default for MqttClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Outbound QoS 1/2 publish waiting for the server
+struct Inflight {
+    publish: Publish,
+    // PUBREC received, waiting for PUBCOMP
+    released: bool,
+    reply: Reply,
+}
+
+// Owns the socket, like Link on the server side
+struct Connection {
+    io: Box<dyn S>,
+    addr: String,
+    options: Connect,
+    reconnect: Option<(Duration, Duration)>,
+    events: UnboundedSender<Event>,
+    codec: MqttCodec,
+    read: BytesMut,
+    write: BytesMut,
+    keepalive: Duration,
+    last_write: Instant,
+    pinging: bool,
+    next_id: u16,
+    inflight: VecDeque<(u16, Inflight)>,
+    // Requests held back by the receive maximum or a lost connection
+    waiting: VecDeque<(Packet, Reply)>,
+    // SUBSCRIBE and UNSUBSCRIBE waiting for their acknowledgement
+    pending: HashMap<u16, (Packet, Reply)>,
+    // Granted subscriptions, replayed when the server lost the session
+    subscriptions: HashMap<String, (Subscription, Option<SubscribeProperties>)>,
+    // QoS 2 packet ids received but not yet released
+    incoming: HashSet<u16>,
+    aliases: HashMap<u16, String>,
+    receive_maximum: u16,
+    max_packet_size: u32,
+}
+impl Connection {
+    fn new(io: Box<dyn S>, addr: &str, options: Connect, events: UnboundedSender<Event>) -> Self {
+        let mut codec = MqttCodec::new(options.protocol_version, u32::MAX);
+        if let Some(max) = options.properties.as_ref().and_then(|p| p.max_packet_size) {
+            codec.max_packet_size = max;
+        }
+        Connection {
+            io,
+            addr: addr.to_owned(),
+            keepalive: Duration::from_secs(options.keepalive as u64),
+            options,
+            reconnect: None,
+            events,
+            codec,
+            read: BytesMut::with_capacity(10 * 1024),
+            write: BytesMut::with_capacity(10 * 1024),
+            last_write: Instant::now(),
+            pinging: false,
+            next_id: 0,
+            inflight: VecDeque::new(),
+            waiting: VecDeque::new(),
+            pending: HashMap::new(),
+            subscriptions: HashMap::new(),
+            incoming: HashSet::new(),
+            aliases: HashMap::new(),
+            receive_maximum: 65535,
+            max_packet_size: u32::MAX,
+        }
+    }
+
+    async fn handshake(&mut self) -> Result<ConnAck, Error> {
+        self.write_packet(Packet::Connect(self.options.clone()))
+            .await?;
+        let connack = match timeout(CONNECT_TIMEOUT, self.read_packet()).await?? {
+            Packet::ConnAck(connack) => connack,
+            _ => {
+                return Err(Error::Violation(
+                    ReasonCode::ProtocolError,
+                    "expected CONNACK".to_owned(),
+                ))
+            }
+        };
+        if connack.reason_code != ReasonCode::Success {
+            return Err(Error::Rejected(connack.reason_code));
+        }
+        self.keepalive = Duration::from_secs(self.options.keepalive as u64);
+        self.receive_maximum = 65535;
+        self.max_packet_size = u32::MAX;
+        if let Some(props) = &connack.properties {
+            if let Some(client_id) = &props.assigned_client_identifier {
+                self.options.client_id = client_id.clone();
+            }
+            if let Some(keepalive) = props.server_keep_alive {
+                self.keepalive = Duration::from_secs(keepalive as u64);
+            }
+            if let Some(receive_maximum) = props.receive_maximum {
+                self.receive_maximum = receive_maximum;
+            }
+            if let Some(max_packet_size) = props.max_packet_size {
+                self.max_packet_size = max_packet_size;
+            }
+        }
+        Ok(connack)
+    }
+
+    async fn run(
+        mut self,
+        mut requests: UnboundedReceiver<(Packet, Reply)>,
+        messages: UnboundedSender<Publish>,
+    ) {
+        // Returning drops the connection, which fails every outstanding call
+        loop {
+            let e = match self.serve(&mut requests, &messages).await {
+                Ok(()) => return,
+                Err(e) => e,
+            };
+            // A newer connection with the same client id won, leave it alone
+            let taken_over = matches!(e, Error::Disconnected(ReasonCode::SessionTakenOver));
+            let _ = self.events.send(Event::Disconnected(e));
+            let Some((min, max)) = self.reconnect.filter(|_| !taken_over) else {
+                return;
+            };
+            if !self.reopen(&mut requests, min, max).await {
+                return;
+            }
+        }
+    }
+
+    async fn serve(
+        &mut self,
+        requests: &mut UnboundedReceiver<(Packet, Reply)>,
+        messages: &UnboundedSender<Publish>,
+    ) -> Result<(), Error> {
+        loop {
+            let ping = self.last_write + self.keepalive;
+            let running = tokio::select! {
+                packet = self.read_packet() => {
+                    self.handle(packet?, messages).await?;
+                    true
+                }
+                request = requests.recv() => match request {
+                    Some((packet, reply)) => self.request(packet, reply).await?,
+                    // Every handle is gone
+                    None => {
+                        self.write_packet(Packet::Disconnect(Disconnect::new())).await?;
+                        false
+                    }
+                },
+                // No pings with keep alive 0
+                _ = sleep_until(ping), if !self.keepalive.is_zero() => {
+                    self.ping().await?;
+                    true
+                }
+            };
+            if !running {
+                return Ok(());
+            }
+        }
+    }
+
+    // Returns false when the client disconnected while offline
+    async fn reopen(
+        &mut self,
+        requests: &mut UnboundedReceiver<(Packet, Reply)>,
+        min: Duration,
+        max: Duration,
+    ) -> bool {
+        let mut backoff = min;
+        loop {
+            let delay = jitter(backoff);
+            let _ = self.events.send(Event::Reconnecting(delay));
+            let deadline = Instant::now() + delay;
+            // Requests made while offline wait for the next connection
+            loop {
+                tokio::select! {
+                    request = requests.recv() => match request {
+                        Some((Packet::Disconnect(_), reply)) => {
+                            let _ = reply.send(Ok(Packet::None));
+                            return false;
+                        }
+                        Some(request) => self.waiting.push_back(request),
+                        None => return false,
+                    },
+                    _ = sleep_until(deadline) => break,
+                }
+            }
+
+            match self.open().await {
+                Ok(connack) => {
+                    let session_present = connack.session_present;
+                    let _ = self.events.send(Event::Connected(connack));
+                    match self.resume(session_present).await {
+                        Ok(()) => return true,
+                        Err(e) => {
+                            let _ = self.events.send(Event::Disconnected(e));
+                        }
+                    }
+                }
+                Err(e) => {
+                    let _ = self.events.send(Event::Disconnected(e));
+                }
+            }
+            backoff = (backoff * 2).min(max);
+        }
+    }
+
+    async fn open(&mut self) -> Result<ConnAck, Error> {
+        let stream = TcpStream::connect(&self.addr).await?;
+        self.io = Box::new(stream);
+        self.read.clear();
+        self.write.clear();
+        self.pinging = false;
+        // Topic aliases only live as long as the network connection
+        self.aliases.clear();
+        self.handshake().await
+    }
+
+    // Picks up where the lost connection stopped
+    async fn resume(&mut self, session_present: bool) -> Result<(), Error> {
+        let pending: Vec<_> = self
+            .pending
+            .values()
+            .map(|(packet, _)| packet.clone())
+            .collect();
+
+        if !session_present {
+            // The server forgot the session, so its QoS 2 state and subscriptions are gone
+            self.incoming.clear();
+            let (released, inflight): (VecDeque<_>, VecDeque<_>) = self
+                .inflight
+                .drain(..)
+                .partition(|(_, inflight)| inflight.released);
+            self.inflight = inflight;
+            // PUBREC already confirmed these
+            for (_, inflight) in released {
+                let _ = inflight.reply.send(Ok(Packet::PubComp(PubComp::new())));
+            }
+
+            let subscriptions: Vec<_> = self.subscriptions.values().cloned().collect();
+            for (subscription, properties) in subscriptions {
+                let packet_id = self.next_id();
+                let mut subscribe = Subscribe::new();
+                subscribe.packet_id = packet_id;
+                subscribe.properties = properties;
+                subscribe.payload.push(subscription);
+                let packet = Packet::Subscribe(subscribe);
+                self.write_packet(packet.clone()).await?;
+                // Nobody waits for the replayed SUBACK
+                let (reply, _) = oneshot::channel();
+                self.pending.insert(packet_id, (packet, reply));
+            }
+        }
+
+        // Unacknowledged publishes go out again in their original order
+        let resend: Vec<_> = self
+            .inflight
+            .iter()
+            .map(|(packet_id, inflight)| match inflight.released {
+                true => {
+                    let mut pubrel = PubRel::new();
+                    pubrel.packet_id = *packet_id;
+                    Packet::PubRel(pubrel)
+                }
+                false => {
+                    let mut publish = inflight.publish.clone();
+                    publish.dup = true;
+                    Packet::Publish(publish)
+                }
+            })
+            .chain(pending)
+            .collect();
+        for packet in resend {
+            self.write_packet(packet).await?;
+        }
+        self.drain().await
+    }
+
+    async fn read_packet(&mut self) -> Result<Packet, Error> {
+        read_packet(&mut *self.io, &mut self.codec, &mut self.read, None).await
+    }
+
+    // Packets over the server's maximum packet size are refused
+    fn pack(&mut self, packet: Packet) -> Result<(), Error> {
+        self.codec.encode(packet, &mut self.write)?;
+        let len = self.write.len();
+        if len > self.max_packet_size as usize {
+            self.write.clear();
+            return Err(Error::Packet(packet::Error::PacketTooLarge(len)));
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), Error> {
+        self.io.write_all(&self.write).await?;
+        self.write.clear();
+        self.last_write = Instant::now();
+        Ok(())
+    }
+
+    async fn write_packet(&mut self, packet: Packet) -> Result<(), Error> {
+        self.pack(packet)?;
+        self.flush().await
+    }
+
+    async fn ping(&mut self) -> Result<(), Error> {
+        if self.pinging {
+            return Err(Error::Io(io::Error::new(
+                ErrorKind::TimedOut,
+                "no PINGRESP from server",
+            )));
+        }
+        self.pinging = true;
+        self.write_packet(Packet::PingReq).await
+    }
+
+    fn position(&self, packet_id: u16) -> Option<usize> {
+        self.inflight.iter().position(|(id, _)| *id == packet_id)
+    }
+
+    fn next_id(&mut self) -> u16 {
+        loop {
+            self.next_id = self.next_id.wrapping_add(1);
+            let id = self.next_id;
+            if id != 0 && self.position(id).is_none() && !self.pending.contains_key(&id) {
+                return id;
+            }
+        }
+    }
+
+    // Returns false once the client asked to disconnect
+    async fn request(&mut self, mut packet: Packet, reply: Reply) -> Result<bool, Error> {
+        if let Packet::Publish(publish) = &packet {
+            if publish.qos > QoS::AtMostOnce && self.inflight.len() >= self.receive_maximum as usize
+            {
+                self.waiting.push_back((packet, reply));
+                return Ok(true);
+            }
+        }
+
+        let packet_id = match &mut packet {
+            Packet::Publish(publish) if publish.qos > QoS::AtMostOnce => {
+                publish.packet_id = self.next_id();
+                publish.packet_id
+            }
+            Packet::Subscribe(subscribe) => {
+                subscribe.packet_id = self.next_id();
+                subscribe.packet_id
+            }
+            Packet::Unsubscribe(unsubscribe) => {
+                unsubscribe.packet_id = self.next_id();
+                unsubscribe.packet_id
+            }
+            _ => 0,
+        };
+        let disconnect = matches!(packet, Packet::Disconnect(_));
+        let kept = match packet_id {
+            0 => None,
+            _ => Some(packet.clone()),
+        };
+
+        if let Err(e) = self.pack(packet) {
+            let _ = reply.send(Err(e));
+            return Ok(true);
+        }
+        // Kept so a lost connection can send them again
+        match kept {
+            None => {
+                let _ = reply.send(Ok(Packet::None));
+            }
+            Some(Packet::Publish(publish)) => {
+                let inflight = Inflight {
+                    publish,
+                    released: false,
+                    reply,
+                };
+                self.inflight.push_back((packet_id, inflight));
+            }
+            Some(packet) => {
+                self.pending.insert(packet_id, (packet, reply));
+            }
+        }
+        self.flush().await?;
+        Ok(!disconnect)
+    }
+
+    async fn handle(
+        &mut self,
+        packet: Packet,
+        messages: &UnboundedSender<Publish>,
+    ) -> Result<(), Error> {
+        match packet {
+            Packet::Publish(mut publish) => {
+                self.resolve_alias(&mut publish)?;
+                let packet_id = publish.packet_id;
+                match publish.qos {
+                    QoS::AtMostOnce => {
+                        let _ = messages.send(publish);
+                    }
+                    QoS::AtLeastOnce => {
+                        let _ = messages.send(publish);
+                        let mut puback = PubAck::new();
+                        puback.packet_id = packet_id;
+                        self.write_packet(Packet::PubAck(puback)).await?;
+                    }
+                    QoS::ExactlyOnce => {
+                        // Delivered once, a retransmission only gets another PUBREC
+                        if self.incoming.insert(packet_id) {
+                            let _ = messages.send(publish);
+                        }
+                        let mut pubrec = PubRec::new();
+                        pubrec.packet_id = packet_id;
+                        self.write_packet(Packet::PubRec(pubrec)).await?;
+                    }
+                }
+            }
+            Packet::PubRel(pubrel) => {
+                self.incoming.remove(&pubrel.packet_id);
+                let mut pubcomp = PubComp::new();
+                pubcomp.packet_id = pubrel.packet_id;
+                self.write_packet(Packet::PubComp(pubcomp)).await?;
+            }
+            Packet::PubAck(puback) => {
+                let packet_id = puback.packet_id;
+                self.complete(packet_id, Packet::PubAck(puback)).await?;
+            }
+            Packet::PubRec(pubrec) => {
+                let packet_id = pubrec.packet_id;
+                if pubrec.reason_code >= ReasonCode::UnspecifiedError {
+                    self.complete(packet_id, Packet::PubRec(pubrec)).await?;
+                } else if let Some(i) = self.position(packet_id) {
+                    self.inflight[i].1.released = true;
+                    let mut pubrel = PubRel::new();
+                    pubrel.packet_id = packet_id;
+                    self.write_packet(Packet::PubRel(pubrel)).await?;
+                }
+            }
+            Packet::PubComp(pubcomp) => {
+                let packet_id = pubcomp.packet_id;
+                self.complete(packet_id, Packet::PubComp(pubcomp)).await?;
+            }
+            Packet::SubAck(suback) => {
+                if let Some((Packet::Subscribe(subscribe), reply)) =
+                    self.pending.remove(&suback.packet_id)
+                {
+                    let granted = subscribe.payload.into_iter().zip(&suback.payload);
+                    for (subscription, reason_code) in granted {
+                        if *reason_code < ReasonCode::UnspecifiedError {
+                            let properties = subscribe.properties.clone();
+                            let topic = subscription.topic.clone();
+                            self.subscriptions.insert(topic, (subscription, properties));
+                        }
+                    }
+                    let _ = reply.send(Ok(Packet::SubAck(suback)));
+                }
+            }
+            Packet::UnsubAck(unsuback) => {
+                if let Some((Packet::Unsubscribe(unsubscribe), reply)) =
+                    self.pending.remove(&unsuback.packet_id)
+                {
+                    for filter in unsubscribe.payload.iter() {
+                        self.subscriptions.remove(filter);
+                    }
+                    let _ = reply.send(Ok(Packet::UnsubAck(unsuback)));
+                }
+            }
+            Packet::PingResp => self.pinging = false,
+            Packet::Disconnect(disconnect) => {
+                return Err(Error::Disconnected(disconnect.reason_code));
+            }
+            _ => {
+                return Err(Error::Violation(
+                    ReasonCode::ProtocolError,
+                    "unexpected packet from server".to_owned(),
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    // Ends a publish flow and frees its slot for a waiting publish
+    async fn complete(&mut self, packet_id: u16, ack: Packet) -> Result<(), Error> {
+        if let Some(i) = self.position(packet_id) {
+            let (_, inflight) = self.inflight.remove(i).unwrap();
+            let _ = inflight.reply.send(Ok(ack));
+        }
+        self.drain().await
+    }
+
+    async fn drain(&mut self) -> Result<(), Error> {
+        while self.inflight.len() < self.receive_maximum as usize {
+            let Some((packet, reply)) = self.waiting.pop_front() else {
+                break;
+            };
+            self.request(packet, reply).await?;
+        }
+        Ok(())
+    }
+
+    fn resolve_alias(&mut self, publish: &mut Publish) -> Result<(), Error> {
+        let alias = publish.properties.as_ref().and_then(|p| p.topic_alias);
+        let Some(alias) = alias else {
+            return Ok(());
+        };
+        if publish.topic_name.is_empty() {
+            match self.aliases.get(&alias) {
+                Some(topic) => publish.topic_name = topic.clone(),
+                None => {
+                    return Err(Error::Violation(
+                        ReasonCode::TopicAliasInvalid,
+                        format!("unknown topic alias {alias}"),
+                    ))
+                }
+            }
+        } else {
+            self.aliases.insert(alias, publish.topic_name.clone());
+        }
+        Ok(())
+    }
+}
+
+// Equal jitter, half the backoff plus a random share of the other half
+fn jitter(backoff: Duration) -> Duration {
+    let half = backoff / 2;
+    half + half.mul_f64(fastrand::f64())
+}
@@ -0,0 +1,213 @@
+use crate::packet::Error::{InvalidPacket, MalformedPacket, PacketTooLarge};
+use crate::*;
+use bytes::{Buf, BytesMut};
+use std::io::ErrorKind;
+use tokio::io::AsyncReadExt;
+use tokio::time::{timeout_at, Instant};
+use tokio_util::codec::{Decoder, Encoder};
+
+// Largest packet the remaining length can describe
+const MAX_PACKET_SIZE: u32 = 1 + 4 + 268_435_455;
+
+// MQTT framing for tokio-util streams and sinks
+#[derive(Debug, Clone)]
+pub struct MqttCodec {
+    // Taken from the first CONNECT that passes through the codec
+    pub version: Version,
+    // Larger inbound packets fail with PacketTooLarge
+    pub max_packet_size: u32,
+    negotiated: bool,
+}
+
+impl MqttCodec {
+    pub fn new(version: Version, max_packet_size: u32) -> Self {
+        Self {
+            version,
+            max_packet_size,
+            negotiated: false,
+        }
+    }
+}
+impl Default for MqttCodec {
+    fn default() -> Self {
+        Self::new(Version::default(), MAX_PACKET_SIZE)
+    }
+}
+
+// Next packet from the stream, shared by the broker and the client. Decodes from
+// the buffer first so it stays cancel safe, reads past the deadline time out
+pub(crate) async fn read_packet(
+    stream: &mut dyn S,
+    codec: &mut MqttCodec,
+    read: &mut BytesMut,
+    deadline: Option<Instant>,
+) -> Result<Packet, Error> {
+    loop {
+        if let Some(packet) = codec.decode(read)? {
+            return Ok(packet);
+        }
+        let n = match deadline {
+            Some(deadline) => timeout_at(deadline, stream.read_buf(read)).await??,
+            None => stream.read_buf(read).await?,
+        };
+        if n == 0 {
+            return Err(Error::Io(io::Error::new(
+                ErrorKind::ConnectionReset,
+                "connection closed by peer",
+            )));
+        }
+    }
+}
+
+impl Decoder for MqttCodec {
+    type Item = Packet;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Packet>, Error> {
+        if src.len() < 2 {
+            return Ok(None);
+        }
+        let byte1 = src[0];
+        let (remaining_len, bytes) = match read_length(src[1..].iter()) {
+            Ok((l, b)) => (l, b),
+            Err(packet::Error::PacketTooShort) => return Ok(None),
+            Err(e) => return Err(Error::Packet(e)),
+        };
+
+        let len = 1 + bytes + remaining_len;
+        if len > self.max_packet_size as usize {
+            return Err(Error::Packet(PacketTooLarge(len)));
+        }
+        if len > src.len() {
+            src.reserve(len - src.len());
+            return Ok(None);
+        }
+
+        let mut packet = src.split_to(len).freeze();
+        packet.advance(1 + bytes);
+
+        // Only PUBLISH carries flags, PUBREL, SUBSCRIBE and UNSUBSCRIBE have them fixed
+        let packet_type = PacketType::try_from(byte1 >> 4)?;
+        let flags = match packet_type {
+            PacketType::Publish => byte1 & 0x0F,
+            PacketType::PubRel | PacketType::Subscribe | PacketType::Unsubscribe => 0x02,
+            _ => 0x00,
+        };
+        if byte1 & 0x0F != flags {
+            return Err(Error::Packet(MalformedPacket(format!(
+                "fixed header flags 0x{:02X}",
+                byte1
+            ))));
+        }
+
+        let packet = match packet_type {
+            PacketType::Connect => {
+                // Set before parsing so a bad CONNECT is refused in the client's version,
+                // a second CONNECT does not change what the link negotiated
+                if !self.negotiated {
+                    self.version = connect_version(&packet);
+                    self.negotiated = true;
+                }
+                let connect = Connect::unpack(packet)?;
+                Packet::Connect(connect)
+            }
+            PacketType::ConnAck => {
+                let connack = ConnAck::unpack(packet, self.version)?;
+                Packet::ConnAck(connack)
+            }
+            PacketType::Publish => {
+                let publish = Publish::unpack(packet, self.version, byte1)?;
+                Packet::Publish(publish)
+            }
+            PacketType::PubAck => {
+                let puback = PubAck::unpack(packet, self.version)?;
+                Packet::PubAck(puback)
+            }
+            PacketType::PubRec => {
+                let pubrec = PubRec::unpack(packet, self.version)?;
+                Packet::PubRec(pubrec)
+            }
+            PacketType::PubRel => {
+                let pubrel = PubRel::unpack(packet, self.version)?;
+                Packet::PubRel(pubrel)
+            }
+            PacketType::PubComp => {
+                let pubcomp = PubComp::unpack(packet, self.version)?;
+                Packet::PubComp(pubcomp)
+            }
+            PacketType::Subscribe => {
+                let subscribe = Subscribe::unpack(packet, self.version)?;
+                Packet::Subscribe(subscribe)
+            }
+            PacketType::SubAck => {
+                let suback = SubAck::unpack(packet, self.version)?;
+                Packet::SubAck(suback)
+            }
+            PacketType::Unsubscribe => {
+                let unsubscribe = Unsubscribe::unpack(packet, self.version)?;
+                Packet::Unsubscribe(unsubscribe)
+            }
+            PacketType::UnsubAck => {
+                let unsuback = UnsubAck::unpack(packet, self.version)?;
+                Packet::UnsubAck(unsuback)
+            }
+            PacketType::PingReq => Packet::PingReq,
+            PacketType::PingResp => Packet::PingResp,
+            PacketType::Disconnect => {
+                let disconnect = Disconnect::unpack(packet, self.version)?;
+                Packet::Disconnect(disconnect)
+            }
+            PacketType::Auth => {
+                let auth = Auth::unpack(packet)?;
+                Packet::Auth(auth)
+            }
+            PacketType::Reserved => {
+                return Err(Error::Packet(InvalidPacket(format!(
+                    "0x{:02X}",
+                    byte1 >> 4
+                ))))
+            }
+        };
+        Ok(Some(packet))
+    }
+}
+
+// Protocol level following the protocol name, unknown levels get a 3.1.1 answer
+fn connect_version(packet: &[u8]) -> Version {
+    let level = match packet {
+        [hi, lo, rest @ ..] => rest.get(u16::from_be_bytes([*hi, *lo]) as usize),
+        _ => None,
+    };
+    level
+        .and_then(|&level| Version::try_from(level).ok())
+        .unwrap_or(Version::V311)
+}
+
+impl Encoder<Packet> for MqttCodec {
+    type Error = Error;
+
+    fn encode(&mut self, packet: Packet, dst: &mut BytesMut) -> Result<(), Error> {
+        match packet {
+            Packet::Connect(connect) => {
+                self.version = connect.protocol_version;
+                connect.pack(dst)?;
+            }
+            Packet::ConnAck(connack) => connack.pack(dst, self.version)?,
+            Packet::Publish(publish) => publish.pack(dst, self.version)?,
+            Packet::PubAck(puback) => puback.pack(dst, self.version)?,
+            Packet::PubRec(pubrec) => pubrec.pack(dst, self.version)?,
+            Packet::PubRel(pubrel) => pubrel.pack(dst, self.version)?,
+            Packet::PubComp(pubcomp) => pubcomp.pack(dst, self.version)?,
+            Packet::Subscribe(subscribe) => subscribe.pack(dst, self.version)?,
+            Packet::SubAck(suback) => suback.pack(dst, self.version)?,
+            Packet::Unsubscribe(unsubscribe) => unsubscribe.pack(dst, self.version)?,
+            Packet::UnsubAck(unsuback) => unsuback.pack(dst, self.version)?,
+            Packet::PingReq => pingreq::pack(dst),
+            Packet::PingResp => pingresp::pack(dst),
+            Packet::Disconnect(disconnect) => disconnect.pack(dst, self.version)?,
+            Packet::Auth(auth) => auth.pack(dst)?,
+            Packet::None => {}
+        }
+        Ok(())
+    }
+}
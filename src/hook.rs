@@ -1,7 +1,7 @@
 use crate::{Error, Packet};
 use std::sync::RwLock;
 
-type HookFn = Box<dyn Fn(Packet) -> Result<Packet, Error> + Send + Sync>;
+type HookFn = Box<dyn Fn(&Packet) -> Result<Packet, Error> + Send + Sync>;
 pub struct Hook(RwLock<Vec<HookFn>>);
 
 impl Hook {
@@ -9,15 +9,18 @@ impl Hook {
         Hook(RwLock::new(Vec::new()))
     }
 
-    pub fn register(&self, hook: impl Fn(Packet) -> Result<Packet, Error> + Send + Sync + 'static) {
+    pub fn register(
+        &self,
+        hook: impl Fn(&Packet) -> Result<Packet, Error> + Send + Sync + 'static,
+    ) {
         let mut hooks = self.0.write().unwrap();
         hooks.push(Box::new(hook));
     }
 
-    pub fn trigger(&self, packet: Packet) -> Result<Packet, Error> {
+    pub fn trigger(&self, packet: &Packet) -> Result<Packet, Error> {
         let hooks = self.0.read().unwrap();
         for hook in hooks.iter() {
-            let result = hook(packet.clone());
+            let result = hook(packet);
             match result {
                 Ok(Packet::None) => continue,
                 _ => return result,
@@ -26,3 +29,8 @@ impl Hook {
         Ok(Packet::None)
     }
 }
+impl Default for Hook {
+    fn default() -> Self {
+        Self::new()
+    }
+}
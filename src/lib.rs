@@ -1,13 +1,27 @@
 mod client;
+mod codec;
 mod hook;
 mod link;
 mod packet;
+mod registry;
+mod retain;
+mod router;
 mod server;
+mod session;
+mod share;
+pub mod topic;
 
+pub use client::*;
+pub use codec::*;
 pub use hook::*;
 pub use link::*;
 pub use packet::*;
+pub use registry::*;
+pub use retain::*;
+pub use router::*;
 pub use server::*;
+pub use session::*;
+pub use share::*;
 
 use num_enum::TryFromPrimitiveError;
 use tokio::io;
@@ -35,4 +49,35 @@ pub enum Error {
     NotConnectPacket,
     #[error("Invalid packet type: {0}")]
     TryFromPacketType(#[from] TryFromPrimitiveError<PacketType>),
+    #[error("Protocol violation: {1}")]
+    Violation(ReasonCode, String),
+    #[error("Rejected by server: {0:?}")]
+    Rejected(ReasonCode),
+    #[error("Connection closed")]
+    ConnectionClosed,
+    #[error("Disconnected by server: {0:?}")]
+    Disconnected(ReasonCode),
+}
+
+impl Error {
+    // Reason code of the DISCONNECT closing the connection, if any
+    pub fn reason_code(&self) -> Option<ReasonCode> {
+        let reason_code = match self {
+            Error::Timeout(_) => ReasonCode::KeepAliveTimeout,
+            Error::Violation(reason_code, _) => *reason_code,
+            Error::TryFromPacketType(_) => ReasonCode::MalformedPacket,
+            Error::Packet(e) => match e {
+                packet::Error::PacketTooLarge(_) => ReasonCode::PacketTooLarge,
+                packet::Error::InvalidTopic(_) => ReasonCode::TopicNameInvalid,
+                packet::Error::ProtocolError(_) => ReasonCode::ProtocolError,
+                packet::Error::InvalidProtocol(_) | packet::Error::InvalidProtocolVersion(_) => {
+                    ReasonCode::UnsupportedProtocolVersion
+                }
+                packet::Error::PayloadTooLong => return None,
+                _ => ReasonCode::MalformedPacket,
+            },
+            _ => return None,
+        };
+        Some(reason_code)
+    }
 }
@@ -1,241 +1,616 @@
-use crate::packet::Error::InvalidPacket;
+use crate::packet::Error::{InvalidTopic, PacketTooLarge};
 use crate::*;
-use bytes::{Buf, BytesMut};
+use bytes::BytesMut;
 
-use std::io::ErrorKind;
+use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::time::timeout;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use tokio::time::{timeout, Instant};
+use tokio_util::codec::{Decoder, Encoder};
+
+// Time allowed for the old link to hand over its session
+const TAKEOVER_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Sent to a link through the registry
+#[derive(Debug)]
+pub(crate) enum Message {
+    Publish(Box<Envelope>),
+    Takeover(bool, oneshot::Sender<Session>),
+    Kick(ReasonCode),
+}
 
 pub struct Link {
     io: Box<dyn S>,
+    codec: MqttCodec,
     read: BytesMut,
     write: BytesMut,
     hook: Arc<Hook>,
+    router: Arc<Router>,
+    config: Arc<Config>,
+    session: Session,
+    will: Option<Publish>,
+    will_delay: u32,
+    // Topic aliases of this connection, set by the client and by the broker
+    aliases: HashMap<u16, String>,
+    outbound_aliases: HashMap<String, u16>,
+    deadline: Instant,
+    // Client id assigned by the broker for an empty one
+    assigned: bool,
     pub version: Version,
     pub client_id: String,
     pub keepalive: Duration,
+    pub receive_maximum: u16,
+    pub topic_alias_max: u16,
+    pub max_packet_size: u32,
 }
 impl Link {
-    pub(crate) fn new(io: Box<dyn S>, hook: Arc<Hook>) -> Self {
+    pub(crate) fn new(
+        io: Box<dyn S>,
+        hook: Arc<Hook>,
+        router: Arc<Router>,
+        config: Arc<Config>,
+    ) -> Self {
+        let keepalive = Duration::from_secs(5);
         Link {
             io,
+            codec: MqttCodec::new(Version::default(), config.max_packet_size),
             hook,
+            router,
+            config,
             read: BytesMut::with_capacity(10 * 1024),
             write: BytesMut::with_capacity(10 * 1024),
+            session: Session::default(),
+            will: None,
+            will_delay: 0,
+            aliases: HashMap::new(),
+            outbound_aliases: HashMap::new(),
+            deadline: Instant::now() + keepalive,
+            assigned: false,
             version: Version::default(),
             client_id: Default::default(),
-            keepalive: Duration::from_secs(5),
+            keepalive,
+            receive_maximum: 65535,
+            topic_alias_max: 0,
+            max_packet_size: u32::MAX,
         }
     }
 
     fn set_keepalive(&mut self, keepalive: u16) {
         let keepalive = (keepalive as f32 * 1.5).ceil() as u64;
         self.keepalive = Duration::from_secs(keepalive);
+        self.deadline = Instant::now() + self.keepalive;
     }
-    async fn read_bytes(&mut self, n: usize) -> Result<usize, Error> {
-        let mut count = 0;
-        loop {
-            let read = self.io.read_buf(&mut self.read).await?;
-            if read == 0 {
-                return Err(Error::Io(io::Error::new(
-                    ErrorKind::ConnectionReset,
-                    "connection closed by peer",
-                )));
-            }
-            count += read;
-            if count >= n {
-                return Ok(count);
-            }
-        }
+    async fn read_packet(&mut self) -> Result<Packet, Error> {
+        // Keep alive 0 turns the keep alive mechanism off
+        let deadline = (!self.keepalive.is_zero()).then_some(self.deadline);
+        let packet = read_packet(&mut *self.io, &mut self.codec, &mut self.read, deadline).await?;
+        self.deadline = Instant::now() + self.keepalive;
+        Ok(packet)
     }
 
-    async fn read_packet(&mut self) -> Result<Packet, Error> {
-        let mut n = 2;
-        loop {
-            timeout(self.keepalive, self.read_bytes(n)).await??;
-
-            // println!(
-            //     "{}",
-            //     self.read
-            //         .to_vec()
-            //         .iter()
-            //         .map(|b| format!("{:02X}", b))
-            //         .collect::<Vec<String>>()
-            //         .join(" ")
-            // );
-
-            let mut read = self.read.iter();
-            let byte1 = *read.next().unwrap();
-            let (remaining_len, bytes) = match read_length(read) {
-                Ok((l, b)) => (l, b),
-                Err(_) => {
-                    n = 1;
-                    continue;
-                }
-            };
+    // Packets over the client's maximum packet size lose their reason string and
+    // user properties, a PUBLISH still too large is discarded
+    fn pack(&mut self, packet: Packet) -> Result<bool, Error> {
+        let publish = matches!(packet, Packet::Publish(_));
+        self.codec.encode(packet, &mut self.write)?;
+        let max_packet_size = self.max_packet_size as usize;
+        if self.write.len() <= max_packet_size {
+            return Ok(true);
+        }
+        let mut encoded = self.write.split();
+        if publish {
+            return Ok(false);
+        }
 
-            let len = 1 + bytes + remaining_len;
-            if len > self.read.len() {
-                n = len - self.read.len();
-                continue;
-            }
+        // Rarely needed, so the packet is read back instead of cloned up front
+        let mut codec = MqttCodec::new(self.codec.version, u32::MAX);
+        if let Some(packet) = codec.decode(&mut encoded)? {
+            self.codec.encode(trim(packet), &mut self.write)?;
+        }
+        if self.write.len() <= max_packet_size {
+            return Ok(true);
+        }
+        let len = self.write.len();
+        self.write.clear();
+        Err(Error::Packet(PacketTooLarge(len)))
+    }
 
-            let mut packet = self.read.split_to(len).freeze();
-            if remaining_len > 0 {
-                packet.advance(1 + bytes);
+    async fn write_packet(&mut self, packet: Packet) -> Result<(), Error> {
+        if self.pack(packet)? {
+            self.io.write_all(&self.write).await?;
+        }
+        self.write.clear();
+        Ok(())
+    }
+    pub async fn serve(mut self) {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let (client, session_present) = match self.connect(tx).await {
+            Ok(connected) => connected,
+            Err(e) => {
+                println!("{e}");
+                self.refuse(&e).await;
+                return;
             }
+        };
+        // Registered from here on, so a failed CONNACK still goes offline properly
+        if let Err(e) = self.ack(session_present, ReasonCode::Success).await {
+            println!("{}: {}", self.client_id, e);
+            self.leave(client, rx);
+            return;
+        }
 
-            let packet = match PacketType::try_from(byte1 >> 4)? {
-                PacketType::Connect => {
-                    let connect = Connect::unpack(packet)?;
-                    Packet::Connect(connect)
-                }
-                PacketType::Publish => {
-                    let publish = Publish::unpack(packet, self.version, byte1)?;
-                    Packet::Publish(publish)
-                }
-                PacketType::PubRel => {
-                    let pubrel = PubRel::unpack(packet, self.version)?;
-                    Packet::PubRel(pubrel)
-                }
-                PacketType::Subscribe => {
-                    let subscribe = Subscribe::unpack(packet, self.version)?;
-                    Packet::Subscribe(subscribe)
-                }
-                PacketType::Unsubscribe => {
-                    let unsubscribe = Unsubscribe::unpack(packet, self.version)?;
-                    Packet::Unsubscribe(unsubscribe)
+        if let Err(e) = self.resend().await {
+            println!("{}: {}", self.client_id, e);
+        }
+        if let Err(e) = self.flush().await {
+            println!("{}: {}", self.client_id, e);
+        }
+        loop {
+            let mut packet = tokio::select! {
+                packet = self.read_packet() => match packet {
+                    Ok(p) => p,
+                    Err(e) => {
+                        println!("{}: {}", self.client_id, e);
+                        self.close(&e).await;
+                        break;
+                    }
+                },
+                Some(message) = rx.recv() => match message {
+                    Message::Publish(envelope) => {
+                        if let Err(e) = self.deliver(*envelope).await {
+                            println!("{}: {}", self.client_id, e);
+                            break;
+                        }
+                        continue;
+                    }
+                    Message::Takeover(clean_start, reply) => {
+                        println!("{}: session taken over", self.client_id);
+                        let _ = self.disconnect(ReasonCode::SessionTakenOver).await;
+                        if let Some(will) = self.will.take() {
+                            if self.will_delay == 0 || clean_start {
+                                self.router.publish(&self.client_id, &will);
+                            }
+                        }
+                        while let Ok(message) = rx.try_recv() {
+                            if let Message::Publish(envelope) = message {
+                                if envelope.publish.qos > QoS::AtMostOnce {
+                                    self.session.enqueue(*envelope);
+                                }
+                            }
+                        }
+                        let _ = reply.send(std::mem::take(&mut self.session));
+                        return;
+                    }
+                    Message::Kick(reason_code) => {
+                        let _ = self.disconnect(reason_code).await;
+                        break;
+                    }
                 }
-                PacketType::PingReq => Packet::PingReq,
-                PacketType::Disconnect => {
-                    let disconnect = Disconnect::unpack(packet, self.version)?;
-                    Packet::Disconnect(disconnect)
+            };
+            // Hooks see the topic name of an aliased PUBLISH
+            if let Packet::Publish(publish) = &mut packet {
+                if let Err(e) = self.resolve_alias(publish) {
+                    println!("{}: {}", self.client_id, e);
+                    self.close(&e).await;
+                    break;
                 }
-                PacketType::Auth => {
-                    let auth = Auth::unpack(packet)?;
-                    Packet::Auth(auth)
+            }
+            let r = self.hook.trigger(&packet);
+            println!("{:?}", r);
+            if let Packet::Disconnect(disconnect) = packet {
+                println!("{}: {:?}", self.client_id, disconnect);
+                let expiry = disconnect
+                    .properties
+                    .and_then(|p| p.session_expiry_interval);
+                if let Some(expiry) = expiry {
+                    // Session expiry cannot be raised from zero at disconnect
+                    if self.session.expiry_interval == 0 && expiry > 0 {
+                        let e = Error::Violation(
+                            ReasonCode::ProtocolError,
+                            "session expiry interval raised from 0".to_owned(),
+                        );
+                        self.close(&e).await;
+                        break;
+                    }
+                    self.session.expiry_interval = expiry;
                 }
-                _ => {
-                    return Err(Error::Packet(InvalidPacket(format!(
-                        "0x{:02X}",
-                        byte1 >> 4
-                    ))))
+                // Only a valid DISCONNECT cancels the will
+                if disconnect.reason_code != ReasonCode::DisconnectWithWillMessage {
+                    self.will = None;
                 }
-            };
+                break;
+            }
+            if let Err(e) = self.handle(packet).await {
+                println!("{}: {}", self.client_id, e);
+                self.close(&e).await;
+                break;
+            }
+        }
+        self.leave(client, rx);
+    }
 
-            return Ok(packet);
+    fn leave(&mut self, client: Client, rx: UnboundedReceiver<Message>) {
+        // Will delay ends early when the session does
+        if let Some(will) = self.will.take() {
+            let delay = self.will_delay.min(self.session.expiry_interval);
+            self.router.will(&self.client_id, will, delay);
         }
+        let session = std::mem::take(&mut self.session);
+        self.router.disconnect(session, client, rx);
     }
 
-    async fn write_packet(&mut self, packet: Packet) -> Result<(), Error> {
+    async fn handle(&mut self, packet: Packet) -> Result<(), Error> {
         match packet {
-            Packet::ConnAck(connack) => {
-                //println!("{:?}", connack);
-                connack.pack(&mut self.write, self.version)?;
+            Packet::PingReq => {
+                //println!("{}: {:?}", self.client_id, packet);
+                self.write_packet(Packet::PingResp).await?;
             }
-            Packet::PingResp => {
-                pingresp::pack(&mut self.write);
-            }
-            Packet::Disconnect(disconnect) => {
-                //println!("{:?}", disconnect);
-                disconnect.pack(&mut self.write, self.version)?;
+            Packet::Publish(publish) => {
+                if publish
+                    .properties
+                    .as_ref()
+                    .is_some_and(|p| !p.sub_identifier.is_empty())
+                {
+                    return Err(Error::Violation(
+                        ReasonCode::ProtocolError,
+                        "subscription identifier in PUBLISH".to_owned(),
+                    ));
+                }
+                if !topic::valid_name(&publish.topic_name) {
+                    return self.reject(publish).await;
+                }
+                // A QoS 2 packet id already received is a duplicate until PUBREL
+                let duplicate = publish.qos == QoS::ExactlyOnce
+                    && self.session.incoming.contains(&publish.packet_id);
+                if publish.qos == QoS::ExactlyOnce && !duplicate {
+                    // Only v5 clients are told the broker's receive maximum
+                    if self.version == Version::V5
+                        && self.session.incoming.len() >= self.config.receive_maximum as usize
+                    {
+                        return Err(Error::Violation(
+                            ReasonCode::RecvMaxExceeded,
+                            "receive maximum exceeded".to_owned(),
+                        ));
+                    }
+                    self.session.incoming.insert(publish.packet_id);
+                }
+                if !duplicate {
+                    self.router.publish(&self.client_id, &publish);
+                }
+                match publish.qos {
+                    QoS::AtMostOnce => {}
+                    QoS::AtLeastOnce => {
+                        let mut puback = PubAck::new();
+                        puback.packet_id = publish.packet_id;
+                        self.write_packet(Packet::PubAck(puback)).await?;
+                    }
+                    QoS::ExactlyOnce => {
+                        let mut pubrec = PubRec::new();
+                        pubrec.packet_id = publish.packet_id;
+                        self.write_packet(Packet::PubRec(pubrec)).await?;
+                    }
+                }
             }
             Packet::PubAck(puback) => {
-                //println!("{:?}", puback);
-                puback.pack(&mut self.write, self.version)?;
+                self.session.puback(puback.packet_id);
+                self.flush().await?;
             }
             Packet::PubRec(pubrec) => {
-                //println!("{:?}", pubrec);
-                pubrec.pack(&mut self.write, self.version)?;
+                // An error reason code ends the QoS 2 flow
+                if pubrec.reason_code >= ReasonCode::UnspecifiedError {
+                    self.session.puback(pubrec.packet_id);
+                    return self.flush().await;
+                }
+                let mut pubrel = PubRel::new();
+                pubrel.packet_id = pubrec.packet_id;
+                if !self.session.pubrec(pubrec.packet_id) {
+                    pubrel.reason_code = ReasonCode::PacketIDNotFound;
+                }
+                self.write_packet(Packet::PubRel(pubrel)).await?;
             }
             Packet::PubComp(pubcomp) => {
-                //println!("{:?}", pubcomp);
-                pubcomp.pack(&mut self.write, self.version)?;
+                self.session.pubcomp(pubcomp.packet_id);
+                self.flush().await?;
+            }
+            Packet::PubRel(pubrel) => {
+                println!("{}: {:?}", self.client_id, pubrel);
+                let mut pubcomp = PubComp::new();
+                pubcomp.packet_id = pubrel.packet_id;
+                if !self.session.incoming.remove(&pubrel.packet_id) {
+                    pubcomp.reason_code = ReasonCode::PacketIDNotFound;
+                }
+                self.write_packet(Packet::PubComp(pubcomp)).await?;
+            }
+            Packet::Subscribe(subscribe) => {
+                println!("{}: {:?}", self.client_id, subscribe);
+                let mut suback = SubAck::new();
+                suback.packet_id = subscribe.packet_id;
+                let mut retained = Vec::new();
+                let sub_identifier = subscribe
+                    .properties
+                    .and_then(|p| p.sub_identifier.first().copied());
+                for subscription in subscribe.payload {
+                    if !topic::valid_filter(&subscription.topic) {
+                        suback.payload.push(ReasonCode::TopicFilterInvalid);
+                        continue;
+                    }
+                    if sub_identifier.is_some() && !self.config.sub_identifier_available {
+                        suback.payload.push(ReasonCode::SubIDNotSupported);
+                        continue;
+                    }
+                    if subscription.no_local && topic::shared(&subscription.topic).is_some() {
+                        return Err(Error::Violation(
+                            ReasonCode::ProtocolError,
+                            "no local on a shared subscription".to_owned(),
+                        ));
+                    }
+                    let reason_code = match subscription.qos {
+                        QoS::AtMostOnce => ReasonCode::Success,
+                        QoS::AtLeastOnce => ReasonCode::GrantedQoS1,
+                        QoS::ExactlyOnce => ReasonCode::GrantedQoS2,
+                    };
+                    let filter = subscription.topic.clone();
+                    let exists = self
+                        .session
+                        .subscriptions
+                        .insert(filter.clone(), subscription.clone())
+                        .is_some();
+                    let replay = match subscription.retain_handling {
+                        RetainHandling::Sub => true,
+                        RetainHandling::NewSub => !exists,
+                        RetainHandling::Never => false,
+                    };
+                    // Retained messages are not sent for shared subscriptions
+                    if replay && topic::shared(&filter).is_none() {
+                        for mut publish in self.router.retained().matches(&filter) {
+                            if subscription.qos < publish.qos {
+                                publish.qos = subscription.qos;
+                            }
+                            if let Some(id) = sub_identifier {
+                                let props = publish
+                                    .properties
+                                    .get_or_insert_with(PublishProperties::new);
+                                props.sub_identifier = vec![id];
+                            }
+                            retained.push(publish);
+                        }
+                    }
+                    self.router
+                        .subscribe(&self.client_id, subscription, sub_identifier);
+                    suback.payload.push(reason_code);
+                }
+                self.write_packet(Packet::SubAck(suback)).await?;
+                for publish in retained {
+                    self.deliver(Envelope::new(publish)).await?;
+                }
             }
-            Packet::SubAck(suback) => {
-                //println!("{:?}", suback);
-                suback.pack(&mut self.write, self.version)?;
+            Packet::Unsubscribe(unsubscribe) => {
+                println!("{}: {:?}", self.client_id, unsubscribe);
+                let mut unsuback = UnsubAck::new();
+                unsuback.packet_id = unsubscribe.packet_id;
+                for filter in unsubscribe.payload {
+                    if !topic::valid_filter(&filter) {
+                        unsuback.payload.push(ReasonCode::TopicFilterInvalid);
+                        continue;
+                    }
+                    self.session.subscriptions.remove(&filter);
+                    let reason_code = match self.router.unsubscribe(&self.client_id, &filter) {
+                        true => ReasonCode::Success,
+                        false => ReasonCode::NoSubscriptionExisted,
+                    };
+                    // Messages queued for a shared subscription go to the rest of the group
+                    if topic::shared(&filter).is_some() {
+                        let released = self.session.release_queued(&filter);
+                        let kept = self.router.release(&self.client_id, released);
+                        self.session.queue.extend(kept);
+                    }
+                    unsuback.payload.push(reason_code);
+                }
+                self.write_packet(Packet::UnsubAck(unsuback)).await?;
             }
-            Packet::UnsubAck(unsuback) => {
-                //println!("{:?}", unsuback);
-                unsuback.pack(&mut self.write, self.version)?;
+            Packet::Connect(_) => {
+                return Err(Error::Violation(
+                    ReasonCode::ProtocolError,
+                    "second CONNECT".to_owned(),
+                ));
             }
-            Packet::Auth(auth) => {
-                //println!("{:?}", auth);
-                auth.pack(&mut self.write)?;
+            Packet::Auth(_) => {
+                return Err(Error::Violation(
+                    ReasonCode::ProtocolError,
+                    "AUTH without authentication method".to_owned(),
+                ));
+            }
+            Packet::ConnAck(_) | Packet::SubAck(_) | Packet::UnsubAck(_) | Packet::PingResp => {
+                return Err(Error::Violation(
+                    ReasonCode::ProtocolError,
+                    "server packet sent by client".to_owned(),
+                ));
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // Replaces an inbound topic alias with its topic name
+    fn resolve_alias(&mut self, publish: &mut Publish) -> Result<(), Error> {
+        let Some(alias) = publish
+            .properties
+            .as_mut()
+            .and_then(|p| p.topic_alias.take())
+        else {
+            return Ok(());
+        };
+        let invalid = || {
+            Error::Violation(
+                ReasonCode::TopicAliasInvalid,
+                format!("invalid topic alias {alias}"),
+            )
+        };
+        if alias == 0 || alias > self.config.topic_alias_max {
+            return Err(invalid());
+        }
+        if publish.topic_name.is_empty() {
+            match self.aliases.get(&alias) {
+                Some(topic_name) => publish.topic_name = topic_name.clone(),
+                None => return Err(invalid()),
             }
-            _ => unreachable!(),
+        } else {
+            self.aliases.insert(alias, publish.topic_name.clone());
+        }
+        Ok(())
+    }
+
+    // Assigns outbound topic aliases up to the client's limit, true for a new alias
+    fn assign_alias(&mut self, publish: &mut Publish) -> bool {
+        if self.version != Version::V5 {
+            return false;
+        }
+        let (alias, new) = match self.outbound_aliases.get(&publish.topic_name) {
+            Some(&alias) => {
+                publish.topic_name.clear();
+                (alias, false)
+            }
+            None if self.outbound_aliases.len() < self.topic_alias_max as usize => {
+                let alias = self.outbound_aliases.len() as u16 + 1;
+                self.outbound_aliases
+                    .insert(publish.topic_name.clone(), alias);
+                (alias, true)
+            }
+            None => return false,
+        };
+        let props = publish
+            .properties
+            .get_or_insert_with(PublishProperties::new);
+        props.topic_alias = Some(alias);
+        new
+    }
+
+    // Invalid topic name: negative ack for v5 QoS 1/2, otherwise disconnect
+    async fn reject(&mut self, publish: Publish) -> Result<(), Error> {
+        if self.version == Version::V5 {
+            match publish.qos {
+                QoS::AtLeastOnce => {
+                    let mut puback = PubAck::new();
+                    puback.packet_id = publish.packet_id;
+                    puback.reason_code = ReasonCode::TopicNameInvalid;
+                    return self.write_packet(Packet::PubAck(puback)).await;
+                }
+                QoS::ExactlyOnce => {
+                    let mut pubrec = PubRec::new();
+                    pubrec.packet_id = publish.packet_id;
+                    pubrec.reason_code = ReasonCode::TopicNameInvalid;
+                    return self.write_packet(Packet::PubRec(pubrec)).await;
+                }
+                QoS::AtMostOnce => {}
+            }
+        }
+        Err(Error::Packet(InvalidTopic(publish.topic_name)))
+    }
+
+    // Only v5 has a server side DISCONNECT
+    async fn disconnect(&mut self, reason_code: ReasonCode) -> Result<(), Error> {
+        if self.version != Version::V5 {
+            return Ok(());
+        }
+        let mut disconnect = Disconnect::new();
+        disconnect.reason_code = reason_code;
+        self.write_packet(Packet::Disconnect(disconnect)).await
+    }
+
+    // Tells a v5 client why the connection is closed
+    async fn close(&mut self, e: &Error) {
+        let Some(reason_code) = e.reason_code() else {
+            return;
+        };
+        if self.version != Version::V5 {
+            return;
+        }
+        let mut disconnect = Disconnect::new();
+        disconnect.reason_code = reason_code;
+        let mut props = DisconnectProperties::new();
+        props.reason_string = Some(e.to_string());
+        disconnect.properties = Some(props);
+        let _ = self.write_packet(Packet::Disconnect(disconnect)).await;
+    }
+
+    async fn deliver(&mut self, envelope: Envelope) -> Result<(), Error> {
+        // Queued until the client acknowledges an inflight message
+        if envelope.publish.qos > QoS::AtMostOnce
+            && self.session.inflight.len() >= self.receive_maximum as usize
+        {
+            self.session.enqueue(envelope);
+            return Ok(());
+        }
+        let (expires_at, share) = (envelope.expires_at, envelope.share.clone());
+        // Expired while waiting in the broker
+        let Some(mut publish) = envelope.open() else {
+            return Ok(());
+        };
+        if publish.qos > QoS::AtMostOnce {
+            publish.packet_id = self.session.next_packet_id();
+        }
+        let packet_id = publish.packet_id;
+        let inflight = (packet_id > 0).then(|| {
+            Inflight::Publish(Box::new(Envelope {
+                publish: publish.clone(),
+                expires_at,
+                share,
+            }))
+        });
+        let topic_name = publish.topic_name.clone();
+        let aliased = self.assign_alias(&mut publish);
+
+        // Too large for the client, dropped as if it had been sent
+        if !self.pack(Packet::Publish(publish))? {
+            if aliased {
+                self.outbound_aliases.remove(&topic_name);
+            }
+            return Ok(());
+        }
+        if let Some(inflight) = inflight {
+            self.session.inflight.push_back((packet_id, inflight));
         }
         self.io.write_all(&self.write).await?;
         self.write.clear();
         Ok(())
     }
-    pub async fn serve(mut self) {
-        match self.connect().await {
-            Ok(_) => loop {
-                let packet = match self.read_packet().await {
-                    Ok(p) => p,
-                    Err(e) => {
-                        println!("{}: {}", self.client_id, e);
-                        return;
-                    }
-                };
-                let r = self.hook.trigger(packet.clone());
-                println!("{:?}", r);
-                match packet {
-                    Packet::PingReq => {
-                        //println!("{}: {:?}", self.client_id, packet);
-                        self.write_packet(Packet::PingResp).await.unwrap();
-                    }
-                    Packet::Publish(publish) => match publish.qos {
-                        QoS::AtMostOnce => {}
-                        QoS::AtLeastOnce => {
-                            let mut puback = PubAck::new();
-                            puback.packet_id = publish.packet_id;
-                            self.write_packet(Packet::PubAck(puback)).await.unwrap()
-                        }
-                        QoS::ExactlyOnce => {
-                            let mut pubrec = PubRec::new();
-                            pubrec.packet_id = publish.packet_id;
-                            self.write_packet(Packet::PubRec(pubrec)).await.unwrap()
-                        }
-                    },
-                    Packet::PubRel(pubrel) => {
-                        println!("{}: {:?}", self.client_id, pubrel);
-                        let mut pubcomp = PubComp::new();
-                        pubcomp.packet_id = pubrel.packet_id;
-                        self.write_packet(Packet::PubComp(pubcomp)).await.unwrap()
-                    }
-                    Packet::Subscribe(subscribe) => {
-                        println!("{}: {:?}", self.client_id, subscribe);
-                        let mut suback = SubAck::new();
-                        suback.packet_id = subscribe.packet_id;
-                        suback.payload.push(ReasonCode::Success);
-                        self.write_packet(Packet::SubAck(suback)).await.unwrap()
-                    }
-                    Packet::Unsubscribe(unsubscribe) => {
-                        println!("{}: {:?}", self.client_id, unsubscribe);
-                        let mut unsuback = UnsubAck::new();
-                        unsuback.packet_id = unsubscribe.packet_id;
-                        unsuback.payload.push(ReasonCode::Success);
-                        self.write_packet(Packet::UnsubAck(unsuback)).await.unwrap()
-                    }
-                    Packet::Disconnect(disconnect) => {
-                        println!("{}: {:?}", self.client_id, disconnect);
-                        break;
-                    }
-                    _ => {}
+
+    async fn flush(&mut self) -> Result<(), Error> {
+        while self.session.inflight.len() < self.receive_maximum as usize {
+            let Some(envelope) = self.session.queue.pop_front() else {
+                break;
+            };
+            self.deliver(envelope).await?;
+        }
+        Ok(())
+    }
+
+    // Retransmits unacknowledged messages of a resumed session
+    async fn resend(&mut self) -> Result<(), Error> {
+        let inflight: Vec<_> = self.session.inflight.iter().cloned().collect();
+        for (packet_id, inflight) in inflight {
+            match inflight {
+                Inflight::Publish(envelope) => {
+                    let mut publish = envelope.publish;
+                    publish.dup = true;
+                    self.assign_alias(&mut publish);
+                    self.write_packet(Packet::Publish(publish)).await?;
+                }
+                Inflight::PubRel => {
+                    let mut pubrel = PubRel::new();
+                    pubrel.packet_id = packet_id;
+                    self.write_packet(Packet::PubRel(pubrel)).await?;
                 }
-            },
-            Err(e) => {
-                println!("{e}")
             }
         }
+        Ok(())
     }
 
-    async fn connect(&mut self) -> Result<bool, Error> {
+    // Registers the client and takes over or resumes its session, true if one is present
+    async fn connect(&mut self, tx: UnboundedSender<Message>) -> Result<(Client, bool), Error> {
         let packet = match self.read_packet().await {
             Ok(p) => p,
             Err(e) => return Err(e),
@@ -251,23 +626,224 @@ impl Link {
         self.client_id = connect.client_id.clone();
         self.set_keepalive(connect.keepalive);
 
-        //let client = Arc::new(self);
-        let r = self.hook.trigger(Packet::Connect(connect));
+        // 3.1 allows 1 to 23 characters, 3.1.1 an empty id only with a clean session
+        let len = self.client_id.chars().count();
+        let invalid = match self.version {
+            Version::V31 => !(1..=23).contains(&len),
+            Version::V311 => len == 0 && !connect.clean_start,
+            Version::V5 => false,
+        };
+        if invalid {
+            return Err(Error::Violation(
+                ReasonCode::ClientIdentifierNotValid,
+                format!("invalid client id {:?}", self.client_id),
+            ));
+        }
+
+        // A hook failing CONNECT refuses the client
+        let r = self.hook.trigger(&Packet::Connect(connect.clone()));
         println!("{:?}", r);
+        if let Err(e) = r {
+            let reason_code = match e {
+                Error::Rejected(reason_code) => reason_code,
+                _ => ReasonCode::NotAuthorized,
+            };
+            return Err(Error::Violation(
+                reason_code,
+                format!("CONNECT refused: {e}"),
+            ));
+        }
+        if self.client_id.is_empty() {
+            self.client_id = self.router.assign_client_id();
+            self.assigned = true;
+        }
+
+        if let Some(props) = &connect.properties {
+            self.receive_maximum = props.receive_maximum.unwrap_or(65535).max(1);
+            self.topic_alias_max = props.topic_alias_max.unwrap_or(0);
+            self.max_packet_size = props.max_packet_size.unwrap_or(u32::MAX);
+        }
+        if connect.will_flag {
+            if !topic::valid_name(&connect.will_topic) {
+                return Err(Error::Violation(
+                    ReasonCode::TopicNameInvalid,
+                    format!("invalid will topic {:?}", connect.will_topic),
+                ));
+            }
+            self.will = Some(will(&connect));
+            self.will_delay = connect
+                .will_properties
+                .as_ref()
+                .and_then(|p| p.will_delay_interval)
+                .unwrap_or(0);
+        }
+
+        // A pending will is cancelled by reconnecting, or sent if the session ends
+        if let Some(will) = self.router.take_will(&self.client_id) {
+            if connect.clean_start {
+                self.router.publish(&self.client_id, &will);
+            }
+        }
+
+        // Session, taken over from the link already online with this client id
+        let client = Client {
+            client_id: self.client_id.clone(),
+            version: self.version,
+            keepalive: connect.keepalive,
+            tx,
+        };
+        let session = match self.router.connect(client.clone()) {
+            Some(old) => self.takeover(old, connect.clean_start).await,
+            None => self.router.resume(&self.client_id),
+        };
+        let session = match connect.clean_start {
+            true => {
+                if let Some(session) = session {
+                    self.router.clear(&session);
+                }
+                None
+            }
+            false => session,
+        };
+        let session_present = session.is_some();
+        self.session = session.unwrap_or_else(|| Session::new(&self.client_id));
+        self.session.expiry_interval = match self.version {
+            Version::V5 => connect
+                .properties
+                .as_ref()
+                .and_then(|p| p.session_expiry_interval)
+                .unwrap_or(0),
+            _ if connect.clean_start => 0,
+            _ => u32::MAX,
+        };
+        Ok((client, session_present))
+    }
+
+    // Answers a CONNECT that failed with a CONNACK carrying the reason where the
+    // version allows one, then closes
+    async fn refuse(&mut self, e: &Error) {
+        // Timeouts and closed connections have no one to answer
+        let reason_code = match e {
+            Error::Packet(_) | Error::TryFromPacketType(_) | Error::Violation(..) => {
+                e.reason_code()
+            }
+            _ => None,
+        };
+        let Some(reason_code) = reason_code else {
+            return;
+        };
+        self.version = self.codec.version;
+        // 3.1.1 closes without a CONNACK unless a return code covers the reason
+        if self.version != Version::V5
+            && !matches!(reason_code.to_connack_v3(), 0x01 | 0x02 | 0x04 | 0x05)
+        {
+            return;
+        }
+        let _ = self.ack(false, reason_code).await;
+    }
 
-        self.ack().await?;
-        Ok(true)
+    async fn takeover(&mut self, old: Client, clean_start: bool) -> Option<Session> {
+        let (reply, session) = oneshot::channel();
+        if old.tx.send(Message::Takeover(clean_start, reply)).is_ok() {
+            if let Ok(Ok(session)) = timeout(TAKEOVER_TIMEOUT, session).await {
+                return Some(session);
+            }
+        }
+        // The old link went offline first, or is stuck writing to a dead peer
+        self.router.resume(&self.client_id)
     }
 
-    async fn ack(&mut self) -> Result<(), Error> {
-        let mut reason_code = ReasonCode::Success;
+    async fn ack(&mut self, session_present: bool, reason_code: ReasonCode) -> Result<(), Error> {
         let mut ack = ConnAck::new();
+        ack.session_present = session_present;
         ack.reason_code = reason_code;
-        //let mut prop = ConnAckProperties::new();
-        //prop.topic_alias_max = Some(300);
-        //ack.properties = Some(prop);
+        if self.version == Version::V5 {
+            let mut prop = ConnAckProperties::new();
+            prop.receive_maximum = Some(self.config.receive_maximum);
+            prop.max_packet_size = Some(self.config.max_packet_size);
+            if self.config.topic_alias_max > 0 {
+                prop.topic_alias_max = Some(self.config.topic_alias_max);
+            }
+            prop.shared_sub_available = Some(1);
+            prop.sub_identifier_available = Some(self.config.sub_identifier_available as u8);
+            if self.assigned && reason_code == ReasonCode::Success {
+                prop.assigned_client_identifier = Some(self.client_id.clone());
+            }
+            ack.properties = Some(prop);
+        }
         let packet = Packet::ConnAck(ack);
         self.write_packet(packet).await?;
         Ok(())
     }
 }
+
+fn will(connect: &Connect) -> Publish {
+    let mut will = Publish::new();
+    will.qos = connect.will_qos;
+    will.retain = connect.will_retain;
+    will.topic_name = connect.will_topic.clone();
+    will.payload = connect.will_payload.clone();
+    if let Some(props) = &connect.will_properties {
+        let mut properties = PublishProperties::new();
+        properties.payload_format_indicator = props.payload_format_indicator;
+        properties.message_expiry_interval = props.message_expiry_interval;
+        properties.content_type = props.content_type.clone();
+        properties.response_topic = props.response_topic.clone();
+        properties.correlation_data = props.correlation_data.clone();
+        properties.user_property = props.user_property.clone();
+        will.properties = Some(properties);
+    }
+    will
+}
+
+// Drops the optional reason string and user properties
+fn trim(packet: Packet) -> Packet {
+    match packet {
+        Packet::ConnAck(mut connack) => {
+            if let Some(props) = connack.properties.as_mut() {
+                props.reason_string = None;
+                props.user_property.clear();
+            }
+            Packet::ConnAck(connack)
+        }
+        Packet::Disconnect(mut disconnect) => {
+            if let Some(props) = disconnect.properties.as_mut() {
+                props.reason_string = None;
+                props.user_property.clear();
+            }
+            Packet::Disconnect(disconnect)
+        }
+        Packet::Auth(mut auth) => {
+            if let Some(props) = auth.properties.as_mut() {
+                props.reason_string = None;
+                props.user_property.clear();
+            }
+            Packet::Auth(auth)
+        }
+        Packet::PubAck(mut puback) => {
+            puback.properties = None;
+            Packet::PubAck(puback)
+        }
+        Packet::PubRec(mut pubrec) => {
+            pubrec.properties = None;
+            Packet::PubRec(pubrec)
+        }
+        Packet::PubRel(mut pubrel) => {
+            pubrel.properties = None;
+            Packet::PubRel(pubrel)
+        }
+        Packet::PubComp(mut pubcomp) => {
+            pubcomp.properties = None;
+            Packet::PubComp(pubcomp)
+        }
+        Packet::SubAck(mut suback) => {
+            suback.properties = None;
+            Packet::SubAck(suback)
+        }
+        Packet::UnsubAck(mut unsuback) => {
+            unsuback.properties = None;
+            Packet::UnsubAck(unsuback)
+        }
+        packet => packet,
+    }
+}
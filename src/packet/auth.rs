@@ -16,7 +16,13 @@ impl Auth {
 
     pub fn unpack(mut read: Bytes) -> Result<Self, Error> {
         let mut auth = Self::new();
-        auth.reason_code = ReasonCode::try_from(read.get_u8())?;
+        if read.is_empty() {
+            return Ok(auth);
+        }
+        auth.reason_code = ReasonCode::try_from(read_u8(&mut read)?)?;
+        if read.is_empty() {
+            return Ok(auth);
+        }
         auth.properties = AuthProperties::unpack(&mut read)?;
         Ok(auth)
     }
@@ -24,7 +30,7 @@ impl Auth {
         let mut props_len = 0;
         let mut props_buf = BytesMut::with_capacity(512);
         if let Some(props) = self.properties {
-            props.pack(&mut props_buf);
+            props.pack(&mut props_buf)?;
             props_len = props_buf.len();
         }
 
@@ -43,7 +49,7 @@ impl Auth {
 #[derive(Debug, Default, Clone)]
 pub struct AuthProperties {
     pub auth_method: Option<String>,
-    pub auth_data: Option<Vec<u8>>,
+    pub auth_data: Option<Bytes>,
     pub reason_string: Option<String>,
     pub user_property: Vec<(String, String)>,
 }
@@ -54,68 +60,57 @@ impl AuthProperties {
             ..Default::default()
         }
     }
+
     pub fn unpack(read: &mut Bytes) -> Result<Option<Self>, Error> {
-        let (len, bytes) = read_length(read.iter())?;
-        read.advance(bytes);
+        let props = Properties::unpack(read, AUTH_PROPERTIES)?;
+        Ok(props.map(Self::from))
+    }
 
-        if len == 0 {
-            return Ok(None);
-        }
+    pub fn pack(self, write: &mut BytesMut) -> Result<(), Error> {
+        Properties::from(self).pack(write)
+    }
+}
 
-        let mut read = read.split_to(len);
+impl From<Properties> for AuthProperties {
+    fn from(props: Properties) -> Self {
         let mut prop = Self::new();
-
-        loop {
-            if read.len() == 0 {
-                return Ok(Some(prop));
-            }
-            let identifier = read.get_u8();
-            match Property::try_from(identifier)? {
-                Property::AuthMethod => {
-                    prop.auth_method = Some(read_string(&mut read)?);
+        for (property, value) in props {
+            match (property, value) {
+                (Property::AuthMethod, PropertyValue::String(v)) => {
+                    prop.auth_method = Some(v);
                 }
-
-                Property::AuthData => {
-                    let len = read.get_u16() as usize;
-                    let read = read.split_to(len);
-                    prop.auth_data = Some(read.to_vec())
+                (Property::AuthData, PropertyValue::Binary(v)) => {
+                    prop.auth_data = Some(v);
                 }
-
-                Property::ReasonString => {
-                    prop.reason_string = Some(read_string(&mut read)?);
+                (Property::ReasonString, PropertyValue::String(v)) => {
+                    prop.reason_string = Some(v);
                 }
-
-                Property::UserProperty => {
-                    let k = read_string(&mut read)?;
-                    let v = read_string(&mut read)?;
+                (Property::UserProperty, PropertyValue::StringPair(k, v)) => {
                     prop.user_property.push((k, v));
                 }
-                _ => unreachable!(),
+                _ => {}
             }
         }
+        prop
     }
+}
 
-    pub fn pack(self, write: &mut BytesMut) {
-        if let Some(auth_method) = self.auth_method {
-            write.put_u8(Property::AuthMethod as u8);
-            write_string(write, &auth_method);
-        }
-
-        if let Some(auth_data) = self.auth_data {
-            write.put_u8(Property::AuthData as u8);
-            write.put_u16(auth_data.len() as u16);
-            write.put_slice(&auth_data);
-        }
-
-        if let Some(reason_string) = self.reason_string {
-            write.put_u8(Property::ReasonString as u8);
-            write_string(write, &reason_string);
-        }
-
-        for (k, v) in self.user_property.iter() {
-            write.put_u8(Property::UserProperty as u8);
-            write_string(write, k);
-            write_string(write, v);
-        }
+impl From<AuthProperties> for Properties {
+    fn from(prop: AuthProperties) -> Self {
+        let mut props = Properties::new();
+        props.set(
+            Property::AuthMethod,
+            prop.auth_method.map(PropertyValue::String),
+        );
+        props.set(
+            Property::AuthData,
+            prop.auth_data.map(PropertyValue::Binary),
+        );
+        props.set(
+            Property::ReasonString,
+            prop.reason_string.map(PropertyValue::String),
+        );
+        props.set_user_property(prop.user_property);
+        props
     }
 }
@@ -1,5 +1,5 @@
 use crate::packet::*;
-use bytes::{BufMut, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
 
 #[derive(Debug, Default, Clone)]
 pub struct ConnAck {
@@ -14,17 +14,34 @@ impl ConnAck {
             ..Default::default()
         }
     }
+    pub fn unpack(mut read: Bytes, version: Version) -> Result<Self, Error> {
+        let mut connack = Self::new();
+        connack.session_present = read_u8(&mut read)? & 0x01 > 0;
+        let code = read_u8(&mut read)?;
+        connack.reason_code = match version {
+            Version::V5 => ReasonCode::try_from(code)?,
+            _ => ReasonCode::from_connack_v3(code)?,
+        };
+        if version == Version::V5 {
+            connack.properties = ConnAckProperties::unpack(&mut read)?;
+        }
+        Ok(connack)
+    }
     pub fn pack(self, write: &mut BytesMut, version: Version) -> Result<(), Error> {
         let mut props_len = 0;
         let mut props_buf = BytesMut::with_capacity(512);
         if let Some(props) = self.properties {
-            props.pack(&mut props_buf);
+            props.pack(&mut props_buf)?;
             props_len = props_buf.len();
         }
 
         let mut buf = BytesMut::with_capacity(512);
-        buf.put_u8(self.session_present as u8);
-        buf.put_u8(self.reason_code as u8);
+        // 3.1 has no session present flag
+        buf.put_u8((self.session_present && version != Version::V31) as u8);
+        buf.put_u8(match version {
+            Version::V5 => self.reason_code as u8,
+            _ => self.reason_code.to_connack_v3(),
+        });
         if version == Version::V5 {
             write_length(&mut buf, props_len)?;
             buf.put(props_buf.freeze());
@@ -43,7 +60,7 @@ pub struct ConnAckProperties {
     pub assigned_client_identifier: Option<String>,
     pub server_keep_alive: Option<u16>,
     pub auth_method: Option<String>,
-    pub auth_data: Option<Vec<u8>>,
+    pub auth_data: Option<Bytes>,
     pub response_info: Option<String>,
     pub server_reference: Option<String>,
     pub reason_string: Option<String>,
@@ -63,92 +80,149 @@ impl ConnAckProperties {
             ..Default::default()
         }
     }
-    pub fn pack(self, write: &mut BytesMut) {
-        if let Some(session_expiry_interval) = self.session_expiry_interval {
-            write.put_u8(Property::SessionExpiryInterval as u8);
-            write.put_u32(session_expiry_interval);
-        }
-
-        if let Some(assigned_client_identifier) = self.assigned_client_identifier {
-            write.put_u8(Property::AssignedClientIdentifier as u8);
-            write_string(write, &assigned_client_identifier);
-        }
-
-        if let Some(server_keep_alive) = self.server_keep_alive {
-            write.put_u8(Property::ServerKeepAlive as u8);
-            write.put_u16(server_keep_alive);
-        }
-
-        if let Some(auth_method) = self.auth_method {
-            write.put_u8(Property::AuthMethod as u8);
-            write_string(write, &auth_method);
-        }
 
-        if let Some(auth_data) = self.auth_data {
-            write.put_u8(Property::AuthData as u8);
-            write.put_u16(auth_data.len() as u16);
-            write.extend_from_slice(&auth_data);
-        }
-
-        if let Some(response_info) = self.response_info {
-            write.put_u8(Property::ResponseInfo as u8);
-            write_string(write, &response_info);
-        }
-
-        if let Some(server_reference) = self.server_reference {
-            write.put_u8(Property::ServerReference as u8);
-            write_string(write, &server_reference);
-        }
-
-        if let Some(reason_string) = self.reason_string {
-            write.put_u8(Property::ReasonString as u8);
-            write_string(write, &reason_string);
-        }
-
-        if let Some(receive_maximum) = self.receive_maximum {
-            write.put_u8(Property::ReceiveMaximum as u8);
-            write.put_u16(receive_maximum);
-        }
-
-        if let Some(topic_alias_max) = self.topic_alias_max {
-            write.put_u8(Property::TopicAliasMax as u8);
-            write.put_u16(topic_alias_max);
-        }
-
-        if let Some(maximum_qos) = self.maximum_qos {
-            write.put_u8(Property::MaximumQoS as u8);
-            write.put_u8(maximum_qos);
-        }
-
-        if let Some(retain_available) = self.retain_available {
-            write.put_u8(Property::RetainAvailable as u8);
-            write.put_u8(retain_available);
-        }
-
-        for (k, v) in self.user_property.iter() {
-            write.put_u8(Property::UserProperty as u8);
-            write_string(write, k);
-            write_string(write, v);
-        }
-
-        if let Some(max_packet_size) = self.max_packet_size {
-            write.put_u8(Property::MaxPacketSize as u8);
-            write.put_u32(max_packet_size);
-        }
+    pub fn unpack(read: &mut Bytes) -> Result<Option<Self>, Error> {
+        let props = Properties::unpack(read, CONNACK_PROPERTIES)?;
+        Ok(props.map(Self::from))
+    }
 
-        if let Some(wildcard_sub_available) = self.wildcard_sub_available {
-            write.put_u8(Property::WildcardSubAvailable as u8);
-            write.put_u8(wildcard_sub_available);
-        }
+    pub fn pack(self, write: &mut BytesMut) -> Result<(), Error> {
+        Properties::from(self).pack(write)
+    }
+}
 
-        if let Some(sub_identifier_available) = self.sub_identifier_available {
-            write.put_u8(Property::SubIdentifierAvailable as u8);
-            write.put_u8(sub_identifier_available);
-        }
+impl From<Properties> for ConnAckProperties {
+    fn from(props: Properties) -> Self {
+        let mut prop = Self::new();
+        for (property, value) in props {
+            match (property, value) {
+                (Property::SessionExpiryInterval, PropertyValue::FourByteInteger(v)) => {
+                    prop.session_expiry_interval = Some(v);
+                }
+                (Property::AssignedClientIdentifier, PropertyValue::String(v)) => {
+                    prop.assigned_client_identifier = Some(v);
+                }
+                (Property::ServerKeepAlive, PropertyValue::TwoByteInteger(v)) => {
+                    prop.server_keep_alive = Some(v);
+                }
+                (Property::AuthMethod, PropertyValue::String(v)) => {
+                    prop.auth_method = Some(v);
+                }
+                (Property::AuthData, PropertyValue::Binary(v)) => {
+                    prop.auth_data = Some(v);
+                }
+                (Property::ResponseInfo, PropertyValue::String(v)) => {
+                    prop.response_info = Some(v);
+                }
+                (Property::ServerReference, PropertyValue::String(v)) => {
+                    prop.server_reference = Some(v);
+                }
+                (Property::ReasonString, PropertyValue::String(v)) => {
+                    prop.reason_string = Some(v);
+                }
+                (Property::ReceiveMaximum, PropertyValue::TwoByteInteger(v)) => {
+                    prop.receive_maximum = Some(v);
+                }
+                (Property::TopicAliasMax, PropertyValue::TwoByteInteger(v)) => {
+                    prop.topic_alias_max = Some(v);
+                }
+                (Property::MaximumQoS, PropertyValue::Byte(v)) => {
+                    prop.maximum_qos = Some(v);
+                }
+                (Property::RetainAvailable, PropertyValue::Byte(v)) => {
+                    prop.retain_available = Some(v);
+                }
+                (Property::UserProperty, PropertyValue::StringPair(k, v)) => {
+                    prop.user_property.push((k, v));
+                }
+                (Property::MaxPacketSize, PropertyValue::FourByteInteger(v)) => {
+                    prop.max_packet_size = Some(v);
+                }
+                (Property::WildcardSubAvailable, PropertyValue::Byte(v)) => {
+                    prop.wildcard_sub_available = Some(v);
+                }
+                (Property::SubIdentifierAvailable, PropertyValue::Byte(v)) => {
+                    prop.sub_identifier_available = Some(v);
+                }
+                (Property::SharedSubAvailable, PropertyValue::Byte(v)) => {
+                    prop.shared_sub_available = Some(v);
+                }
+                _ => {}
+            }
+        }
+        prop
+    }
+}
 
-        if let Some(shared_sub_available) = self.shared_sub_available {
-            write.put_u8(Property::SharedSubAvailable as u8);
-            write.put_u8(shared_sub_available);
-        }
+impl From<ConnAckProperties> for Properties {
+    fn from(prop: ConnAckProperties) -> Self {
+        let mut props = Properties::new();
+        props.set(
+            Property::SessionExpiryInterval,
+            prop.session_expiry_interval
+                .map(PropertyValue::FourByteInteger),
+        );
+        props.set(
+            Property::AssignedClientIdentifier,
+            prop.assigned_client_identifier.map(PropertyValue::String),
+        );
+        props.set(
+            Property::ServerKeepAlive,
+            prop.server_keep_alive.map(PropertyValue::TwoByteInteger),
+        );
+        props.set(
+            Property::AuthMethod,
+            prop.auth_method.map(PropertyValue::String),
+        );
+        props.set(
+            Property::AuthData,
+            prop.auth_data.map(PropertyValue::Binary),
+        );
+        props.set(
+            Property::ResponseInfo,
+            prop.response_info.map(PropertyValue::String),
+        );
+        props.set(
+            Property::ServerReference,
+            prop.server_reference.map(PropertyValue::String),
+        );
+        props.set(
+            Property::ReasonString,
+            prop.reason_string.map(PropertyValue::String),
+        );
+        props.set(
+            Property::ReceiveMaximum,
+            prop.receive_maximum.map(PropertyValue::TwoByteInteger),
+        );
+        props.set(
+            Property::TopicAliasMax,
+            prop.topic_alias_max.map(PropertyValue::TwoByteInteger),
+        );
+        props.set(
+            Property::MaximumQoS,
+            prop.maximum_qos.map(PropertyValue::Byte),
+        );
+        props.set(
+            Property::RetainAvailable,
+            prop.retain_available.map(PropertyValue::Byte),
+        );
+        props.set_user_property(prop.user_property);
+        props.set(
+            Property::MaxPacketSize,
+            prop.max_packet_size.map(PropertyValue::FourByteInteger),
+        );
+        props.set(
+            Property::WildcardSubAvailable,
+            prop.wildcard_sub_available.map(PropertyValue::Byte),
+        );
+        props.set(
+            Property::SubIdentifierAvailable,
+            prop.sub_identifier_available.map(PropertyValue::Byte),
+        );
+        props.set(
+            Property::SharedSubAvailable,
+            prop.shared_sub_available.map(PropertyValue::Byte),
+        );
+        props
     }
 }
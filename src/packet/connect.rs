@@ -1,5 +1,5 @@
 use crate::packet::*;
-use bytes::{Buf, Bytes};
+use bytes::{BufMut, Bytes, BytesMut};
 
 // CONNECT Packet
 #[derive(Debug, Default, Clone)]
@@ -17,9 +17,9 @@ pub struct Connect {
     pub client_id: String,
     pub will_properties: Option<WillProperties>,
     pub will_topic: String,
-    pub will_payload: String,
+    pub will_payload: Bytes,
     pub username: String,
-    pub password: String,
+    pub password: Bytes,
 }
 
 impl Connect {
@@ -39,14 +39,17 @@ impl Connect {
         connect.protocol_name = protocol_name;
 
         // Protocol Version
-        let protocol_version = read.get_u8();
+        let protocol_version = read_u8(&mut read)?;
         connect.protocol_version = match Version::try_from(protocol_version) {
             Ok(v) => v,
             Err(_) => return Err(Error::InvalidProtocolVersion(protocol_version)),
         };
 
         // Connect Flags
-        let connect_flags = read.get_u8();
+        let connect_flags = read_u8(&mut read)?;
+        if connect_flags & 0x01 > 0 {
+            return Err(Error::MalformedPacket("reserved connect flag".to_owned()));
+        }
         connect.username_flag = connect_flags & 0x80 > 0;
         connect.password_flag = connect_flags & 0x40 > 0;
         connect.will_retain = connect_flags & 0x20 > 0;
@@ -54,9 +57,20 @@ impl Connect {
         connect.will_qos = QoS::try_from(qos)?;
         connect.will_flag = connect_flags & 0x04 > 0;
         connect.clean_start = connect_flags & 0x02 > 0;
+        if !connect.will_flag && (connect.will_qos > QoS::AtMostOnce || connect.will_retain) {
+            return Err(Error::MalformedPacket("will flags without will".to_owned()));
+        }
+        if connect.password_flag
+            && !connect.username_flag
+            && connect.protocol_version != Version::V5
+        {
+            return Err(Error::MalformedPacket(
+                "password without user name".to_owned(),
+            ));
+        }
 
         // Keep Alive
-        connect.keepalive = read.get_u16();
+        connect.keepalive = read_u16(&mut read)?;
 
         // Properties
         if connect.protocol_version == Version::V5 {
@@ -72,7 +86,7 @@ impl Connect {
                 connect.will_properties = WillProperties::unpack(&mut read)?;
             }
             connect.will_topic = read_string(&mut read)?;
-            connect.will_payload = read_string(&mut read)?;
+            connect.will_payload = read_binary(&mut read)?;
         }
 
         // User Name
@@ -82,10 +96,81 @@ impl Connect {
 
         // Password
         if connect.password_flag {
-            connect.password = read_string(&mut read)?;
+            connect.password = read_binary(&mut read)?;
         }
         Ok(connect)
     }
+
+    pub fn pack(self, write: &mut BytesMut) -> Result<(), Error> {
+        let version = self.protocol_version;
+
+        // Protocol Name
+        let mut buf = BytesMut::with_capacity(512);
+        let protocol_name = match (self.protocol_name.as_str(), version) {
+            ("", Version::V31) => "MQIsdp",
+            ("", _) => "MQTT",
+            (name, _) => name,
+        };
+        write_string(&mut buf, protocol_name);
+
+        // Protocol Version
+        buf.put_u8(version as u8);
+
+        // Connect Flags
+        let mut connect_flags = 0;
+        connect_flags |= (self.username_flag as u8) << 7;
+        connect_flags |= (self.password_flag as u8) << 6;
+        connect_flags |= (self.will_retain as u8) << 5;
+        connect_flags |= (self.will_qos as u8) << 3;
+        connect_flags |= (self.will_flag as u8) << 2;
+        connect_flags |= (self.clean_start as u8) << 1;
+        buf.put_u8(connect_flags);
+
+        // Keep Alive
+        buf.put_u16(self.keepalive);
+
+        // Properties
+        if version == Version::V5 {
+            let mut props_buf = BytesMut::with_capacity(512);
+            if let Some(props) = self.properties {
+                props.pack(&mut props_buf)?;
+            }
+            write_length(&mut buf, props_buf.len())?;
+            buf.put(props_buf.freeze());
+        }
+
+        // Client ID
+        write_string(&mut buf, &self.client_id);
+
+        // Will
+        if self.will_flag {
+            if version == Version::V5 {
+                let mut props_buf = BytesMut::with_capacity(512);
+                if let Some(props) = self.will_properties {
+                    props.pack(&mut props_buf)?;
+                }
+                write_length(&mut buf, props_buf.len())?;
+                buf.put(props_buf.freeze());
+            }
+            write_string(&mut buf, &self.will_topic);
+            write_binary(&mut buf, &self.will_payload);
+        }
+
+        // User Name
+        if self.username_flag {
+            write_string(&mut buf, &self.username);
+        }
+
+        // Password
+        if self.password_flag {
+            write_binary(&mut buf, &self.password);
+        }
+
+        write.put_u8((PacketType::Connect as u8) << 4);
+        write_length(write, buf.len())?;
+        write.put(buf.freeze());
+        Ok(())
+    }
 }
 
 #[derive(Debug, Default, Clone)]
@@ -98,7 +183,7 @@ pub struct ConnectProperties {
     pub request_problem_info: Option<u8>,
     pub user_property: Vec<(String, String)>,
     pub auth_method: Option<String>,
-    pub auth_data: Option<Vec<u8>>,
+    pub auth_data: Option<Bytes>,
 }
 impl ConnectProperties {
     pub fn new() -> Self {
@@ -106,65 +191,102 @@ impl ConnectProperties {
             ..Default::default()
         }
     }
-    pub fn unpack(read: &mut Bytes) -> Result<Option<Self>, Error> {
-        let (len, bytes) = read_length(read.iter())?;
-        read.advance(bytes);
 
-        if len == 0 || len > read.len() {
-            return Ok(None);
+    pub fn unpack(read: &mut Bytes) -> Result<Option<Self>, Error> {
+        let prop = Properties::unpack(read, CONNECT_PROPERTIES)?.map(Self::from);
+        if let Some(prop) = &prop {
+            if prop.receive_maximum == Some(0) {
+                return Err(Error::ProtocolError("receive maximum 0".to_owned()));
+            }
+            if prop.max_packet_size == Some(0) {
+                return Err(Error::ProtocolError("maximum packet size 0".to_owned()));
+            }
         }
+        Ok(prop)
+    }
 
-        let mut read = read.split_to(len);
-        let mut prop = Self::new();
+    pub fn pack(self, write: &mut BytesMut) -> Result<(), Error> {
+        Properties::from(self).pack(write)
+    }
+}
 
-        loop {
-            if read.len() == 0 {
-                return Ok(Some(prop));
-            }
-            let identifier = read.get_u8();
-            match Property::try_from(identifier)? {
-                Property::SessionExpiryInterval => {
-                    prop.session_expiry_interval = Some(read.get_u32());
+impl From<Properties> for ConnectProperties {
+    fn from(props: Properties) -> Self {
+        let mut prop = Self::new();
+        for (property, value) in props {
+            match (property, value) {
+                (Property::SessionExpiryInterval, PropertyValue::FourByteInteger(v)) => {
+                    prop.session_expiry_interval = Some(v);
                 }
-
-                Property::ReceiveMaximum => {
-                    prop.receive_maximum = Some(read.get_u16());
+                (Property::ReceiveMaximum, PropertyValue::TwoByteInteger(v)) => {
+                    prop.receive_maximum = Some(v);
                 }
-
-                Property::MaxPacketSize => {
-                    prop.max_packet_size = Some(read.get_u32());
+                (Property::MaxPacketSize, PropertyValue::FourByteInteger(v)) => {
+                    prop.max_packet_size = Some(v);
                 }
-
-                Property::TopicAliasMax => {
-                    prop.topic_alias_max = Some(read.get_u16());
+                (Property::TopicAliasMax, PropertyValue::TwoByteInteger(v)) => {
+                    prop.topic_alias_max = Some(v);
                 }
-
-                Property::RequestResponseInfo => {
-                    prop.request_response_info = Some(read.get_u8());
+                (Property::RequestResponseInfo, PropertyValue::Byte(v)) => {
+                    prop.request_response_info = Some(v);
                 }
-
-                Property::RequestProblemInfo => {
-                    prop.request_problem_info = Some(read.get_u8());
+                (Property::RequestProblemInfo, PropertyValue::Byte(v)) => {
+                    prop.request_problem_info = Some(v);
                 }
-
-                Property::UserProperty => {
-                    let k = read_string(&mut read)?;
-                    let v = read_string(&mut read)?;
+                (Property::UserProperty, PropertyValue::StringPair(k, v)) => {
                     prop.user_property.push((k, v));
                 }
-
-                Property::AuthMethod => {
-                    prop.auth_method = Some(read_string(&mut read)?);
+                (Property::AuthMethod, PropertyValue::String(v)) => {
+                    prop.auth_method = Some(v);
                 }
-
-                Property::AuthData => {
-                    let len = read.get_u16() as usize;
-                    let read = read.split_to(len);
-                    prop.auth_data = Some(read.to_vec());
+                (Property::AuthData, PropertyValue::Binary(v)) => {
+                    prop.auth_data = Some(v);
                 }
-                _ => unreachable!(),
+                _ => {}
             }
         }
+        prop
+    }
+}
+
+impl From<ConnectProperties> for Properties {
+    fn from(prop: ConnectProperties) -> Self {
+        let mut props = Properties::new();
+        props.set(
+            Property::SessionExpiryInterval,
+            prop.session_expiry_interval
+                .map(PropertyValue::FourByteInteger),
+        );
+        props.set(
+            Property::ReceiveMaximum,
+            prop.receive_maximum.map(PropertyValue::TwoByteInteger),
+        );
+        props.set(
+            Property::MaxPacketSize,
+            prop.max_packet_size.map(PropertyValue::FourByteInteger),
+        );
+        props.set(
+            Property::TopicAliasMax,
+            prop.topic_alias_max.map(PropertyValue::TwoByteInteger),
+        );
+        props.set(
+            Property::RequestResponseInfo,
+            prop.request_response_info.map(PropertyValue::Byte),
+        );
+        props.set(
+            Property::RequestProblemInfo,
+            prop.request_problem_info.map(PropertyValue::Byte),
+        );
+        props.set_user_property(prop.user_property);
+        props.set(
+            Property::AuthMethod,
+            prop.auth_method.map(PropertyValue::String),
+        );
+        props.set(
+            Property::AuthData,
+            prop.auth_data.map(PropertyValue::Binary),
+        );
+        props
     }
 }
 
@@ -172,7 +294,7 @@ impl ConnectProperties {
 pub struct WillProperties {
     pub content_type: Option<String>,
     pub response_topic: Option<String>,
-    pub correlation_data: Option<Vec<u8>>,
+    pub correlation_data: Option<Bytes>,
     pub will_delay_interval: Option<u32>,
     pub message_expiry_interval: Option<u32>,
     pub payload_format_indicator: Option<u8>,
@@ -184,57 +306,79 @@ impl WillProperties {
             ..Default::default()
         }
     }
+
     pub fn unpack(read: &mut Bytes) -> Result<Option<Self>, Error> {
-        let (len, bytes) = read_length(read.iter())?;
-        read.advance(bytes);
+        let props = Properties::unpack(read, WILL_PROPERTIES)?;
+        Ok(props.map(Self::from))
+    }
 
-        if len == 0 || len > read.len() {
-            return Ok(None);
-        }
+    pub fn pack(self, write: &mut BytesMut) -> Result<(), Error> {
+        Properties::from(self).pack(write)
+    }
+}
 
-        let mut read = read.split_to(len);
+impl From<Properties> for WillProperties {
+    fn from(props: Properties) -> Self {
         let mut prop = Self::new();
-
-        loop {
-            if read.len() == 0 {
-                return Ok(Some(prop));
-            }
-            let identifier = read.get_u8();
-            match Property::try_from(identifier)? {
-                Property::ContentType => {
-                    prop.content_type = Some(read_string(&mut read)?);
+        for (property, value) in props {
+            match (property, value) {
+                (Property::ContentType, PropertyValue::String(v)) => {
+                    prop.content_type = Some(v);
                 }
-
-                Property::ResponseTopic => {
-                    prop.response_topic = Some(read_string(&mut read)?);
+                (Property::ResponseTopic, PropertyValue::String(v)) => {
+                    prop.response_topic = Some(v);
                 }
-
-                Property::CorrelationData => {
-                    let len = read.get_u16() as usize;
-                    let read = read.split_to(len);
-                    prop.correlation_data = Some(read.to_vec())
+                (Property::CorrelationData, PropertyValue::Binary(v)) => {
+                    prop.correlation_data = Some(v);
                 }
-
-                Property::WillDelayInterval => {
-                    prop.will_delay_interval = Some(read.get_u32());
+                (Property::WillDelayInterval, PropertyValue::FourByteInteger(v)) => {
+                    prop.will_delay_interval = Some(v);
                 }
-
-                Property::MessageExpiryInterval => {
-                    prop.message_expiry_interval = Some(read.get_u32());
+                (Property::MessageExpiryInterval, PropertyValue::FourByteInteger(v)) => {
+                    prop.message_expiry_interval = Some(v);
                 }
-
-                Property::PayloadFormatIndicator => {
-                    prop.payload_format_indicator = Some(read.get_u8());
+                (Property::PayloadFormatIndicator, PropertyValue::Byte(v)) => {
+                    prop.payload_format_indicator = Some(v);
                 }
-
-                Property::UserProperty => {
-                    let k = read_string(&mut read)?;
-                    let v = read_string(&mut read)?;
+                (Property::UserProperty, PropertyValue::StringPair(k, v)) => {
                     prop.user_property.push((k, v));
                 }
-
-                _ => unreachable!(),
+                _ => {}
             }
         }
+        prop
+    }
+}
+
+impl From<WillProperties> for Properties {
+    fn from(prop: WillProperties) -> Self {
+        let mut props = Properties::new();
+        props.set(
+            Property::ContentType,
+            prop.content_type.map(PropertyValue::String),
+        );
+        props.set(
+            Property::ResponseTopic,
+            prop.response_topic.map(PropertyValue::String),
+        );
+        props.set(
+            Property::CorrelationData,
+            prop.correlation_data.map(PropertyValue::Binary),
+        );
+        props.set(
+            Property::WillDelayInterval,
+            prop.will_delay_interval.map(PropertyValue::FourByteInteger),
+        );
+        props.set(
+            Property::MessageExpiryInterval,
+            prop.message_expiry_interval
+                .map(PropertyValue::FourByteInteger),
+        );
+        props.set(
+            Property::PayloadFormatIndicator,
+            prop.payload_format_indicator.map(PropertyValue::Byte),
+        );
+        props.set_user_property(prop.user_property);
+        props
     }
 }
@@ -15,8 +15,11 @@ impl Disconnect {
     }
     pub fn unpack(mut read: Bytes, version: Version) -> Result<Self, Error> {
         let mut disconnect = Self::new();
-        if version == Version::V5 {
-            disconnect.reason_code = ReasonCode::try_from(read.get_u8())?;
+        if version == Version::V5 && !read.is_empty() {
+            disconnect.reason_code = ReasonCode::try_from(read_u8(&mut read)?)?;
+            if read.is_empty() {
+                return Ok(disconnect);
+            }
             disconnect.properties = DisconnectProperties::unpack(&mut read)?;
         }
         Ok(disconnect)
@@ -26,7 +29,7 @@ impl Disconnect {
         let mut props_len = 0;
         let mut props_buf = BytesMut::with_capacity(512);
         if let Some(props) = self.properties {
-            props.pack(&mut props_buf);
+            props.pack(&mut props_buf)?;
             props_len = props_buf.len();
         }
 
@@ -57,65 +60,58 @@ impl DisconnectProperties {
             ..Default::default()
         }
     }
+
     pub fn unpack(read: &mut Bytes) -> Result<Option<Self>, Error> {
-        let (len, bytes) = read_length(read.iter())?;
-        read.advance(bytes);
+        let props = Properties::unpack(read, DISCONNECT_PROPERTIES)?;
+        Ok(props.map(Self::from))
+    }
 
-        if len == 0 {
-            return Ok(None);
-        }
+    pub fn pack(self, write: &mut BytesMut) -> Result<(), Error> {
+        Properties::from(self).pack(write)
+    }
+}
 
-        let mut read = read.split_to(len);
+impl From<Properties> for DisconnectProperties {
+    fn from(props: Properties) -> Self {
         let mut prop = Self::new();
-
-        loop {
-            if read.len() == 0 {
-                return Ok(Some(prop));
-            }
-            let identifier = read.get_u8();
-            match Property::try_from(identifier)? {
-                Property::SessionExpiryInterval => {
-                    prop.session_expiry_interval = Some(read.get_u32());
+        for (property, value) in props {
+            match (property, value) {
+                (Property::SessionExpiryInterval, PropertyValue::FourByteInteger(v)) => {
+                    prop.session_expiry_interval = Some(v);
                 }
-
-                Property::ServerReference => {
-                    prop.server_reference = Some(read_string(&mut read)?);
+                (Property::ServerReference, PropertyValue::String(v)) => {
+                    prop.server_reference = Some(v);
                 }
-
-                Property::ReasonString => {
-                    prop.reason_string = Some(read_string(&mut read)?);
+                (Property::ReasonString, PropertyValue::String(v)) => {
+                    prop.reason_string = Some(v);
                 }
-
-                Property::UserProperty => {
-                    let k = read_string(&mut read)?;
-                    let v = read_string(&mut read)?;
+                (Property::UserProperty, PropertyValue::StringPair(k, v)) => {
                     prop.user_property.push((k, v));
                 }
-                _ => unreachable!(),
+                _ => {}
             }
         }
+        prop
     }
+}
 
-    pub fn pack(self, write: &mut BytesMut) {
-        if let Some(session_expiry_interval) = self.session_expiry_interval {
-            write.put_u8(Property::SessionExpiryInterval as u8);
-            write.put_u32(session_expiry_interval);
-        }
-
-        if let Some(server_reference) = self.server_reference {
-            write.put_u8(Property::ServerReference as u8);
-            write_string(write, &server_reference);
-        }
-
-        if let Some(reason_string) = self.reason_string {
-            write.put_u8(Property::ReasonString as u8);
-            write_string(write, &reason_string);
-        }
-
-        for (k, v) in self.user_property.iter() {
-            write.put_u8(Property::UserProperty as u8);
-            write_string(write, k);
-            write_string(write, v);
-        }
+impl From<DisconnectProperties> for Properties {
+    fn from(prop: DisconnectProperties) -> Self {
+        let mut props = Properties::new();
+        props.set(
+            Property::SessionExpiryInterval,
+            prop.session_expiry_interval
+                .map(PropertyValue::FourByteInteger),
+        );
+        props.set(
+            Property::ServerReference,
+            prop.server_reference.map(PropertyValue::String),
+        );
+        props.set(
+            Property::ReasonString,
+            prop.reason_string.map(PropertyValue::String),
+        );
+        props.set_user_property(prop.user_property);
+        props
     }
 }
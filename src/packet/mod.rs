@@ -3,6 +3,7 @@ mod connack;
 mod connect;
 mod disconnect;
 mod ping;
+mod property;
 mod puback;
 mod pubcomp;
 mod publish;
@@ -23,6 +24,7 @@ pub use connack::*;
 pub use connect::*;
 pub use disconnect::*;
 pub use ping::*;
+pub use property::*;
 pub use puback::*;
 pub use pubcomp::*;
 pub use publish::*;
@@ -34,15 +36,24 @@ pub use unsuback::*;
 pub use unsubscribe::*;
 
 #[derive(Debug, thiserror::Error)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     #[error("String is not utf8: {0}")]
     NotUtf8(#[from] FromUtf8Error),
     #[error("Packet is too short")]
     PacketTooShort,
+    #[error("Malformed packet: {0}")]
+    MalformedPacket(String),
+    #[error("Protocol error: {0}")]
+    ProtocolError(String),
     #[error("Payload is too long")]
     PayloadTooLong,
+    #[error("Packet is too large: {0}")]
+    PacketTooLarge(usize),
     #[error("Invalid packet: {0}")]
     InvalidPacket(String),
+    #[error("Invalid topic: {0}")]
+    InvalidTopic(String),
     #[error("Invalid protocol: {0}")]
     InvalidProtocol(String),
     #[error("Invalid protocol version: {0}")]
@@ -93,31 +104,45 @@ pub enum Packet {
     Auth(Auth),
     None,
 }
-#[derive(Debug, PartialEq, PartialOrd, Clone, TryFromPrimitive)]
+
+impl Packet {
+    // Generic view of the packet properties, for logging and hooks
+    pub fn properties(&self) -> Option<Properties> {
+        match self {
+            Packet::Connect(p) => p.properties.clone().map(Properties::from),
+            Packet::ConnAck(p) => p.properties.clone().map(Properties::from),
+            Packet::Publish(p) => p.properties.clone().map(Properties::from),
+            Packet::PubAck(p) => p.properties.clone().map(Properties::from),
+            Packet::PubRec(p) => p.properties.clone().map(Properties::from),
+            Packet::PubRel(p) => p.properties.clone().map(Properties::from),
+            Packet::PubComp(p) => p.properties.clone().map(Properties::from),
+            Packet::Subscribe(p) => p.properties.clone().map(Properties::from),
+            Packet::SubAck(p) => p.properties.clone().map(Properties::from),
+            Packet::Unsubscribe(p) => p.properties.clone().map(Properties::from),
+            Packet::UnsubAck(p) => p.properties.clone().map(Properties::from),
+            Packet::Disconnect(p) => p.properties.clone().map(Properties::from),
+            Packet::Auth(p) => p.properties.clone().map(Properties::from),
+            Packet::PingReq | Packet::PingResp | Packet::None => None,
+        }
+    }
+}
+#[derive(Debug, Default, PartialEq, PartialOrd, Copy, Clone, TryFromPrimitive)]
 #[repr(u8)]
 pub enum QoS {
+    #[default]
     AtMostOnce = 0,
     AtLeastOnce,
     ExactlyOnce,
 }
-impl Default for QoS {
-    fn default() -> Self {
-        Self::AtMostOnce
-    }
-}
-#[derive(Debug, PartialEq, Copy, Clone, TryFromPrimitive)]
+#[derive(Debug, Default, PartialEq, Copy, Clone, TryFromPrimitive)]
 #[repr(u8)]
 pub enum Version {
     V31 = 3,
     V311,
+    #[default]
     V5,
 }
-impl Default for Version {
-    fn default() -> Self {
-        Self::V5
-    }
-}
-#[derive(Debug, TryFromPrimitive)]
+#[derive(Debug, PartialEq, Copy, Clone, TryFromPrimitive)]
 #[repr(u8)]
 pub enum Property {
     PayloadFormatIndicator = 0x01,
@@ -148,9 +173,10 @@ pub enum Property {
     SubIdentifierAvailable = 0x29,
     SharedSubAvailable = 0x2A,
 }
-#[derive(Debug, PartialEq, PartialOrd, Copy, Clone, TryFromPrimitive)]
+#[derive(Debug, Default, PartialEq, PartialOrd, Copy, Clone, TryFromPrimitive)]
 #[repr(u8)]
 pub enum ReasonCode {
+    #[default]
     Success = 0x00,
     GrantedQoS1 = 0x01,
     GrantedQoS2 = 0x02,
@@ -195,26 +221,101 @@ pub enum ReasonCode {
     SubIDNotSupported = 0xA1,
     WildcardSubNotSupported = 0xA2,
 }
-impl Default for ReasonCode {
-    fn default() -> Self {
-        Self::Success
+
+// MQTT 3.1 and 3.1.1 only have a few return codes, v5 reason codes map onto them
+impl ReasonCode {
+    pub fn to_connack_v3(self) -> u8 {
+        match self {
+            ReasonCode::Success => 0x00,
+            ReasonCode::UnsupportedProtocolVersion => 0x01,
+            ReasonCode::ClientIdentifierNotValid => 0x02,
+            ReasonCode::BadUserNameOrPassword | ReasonCode::BadAuthMethod => 0x04,
+            ReasonCode::NotAuthorized | ReasonCode::Banned => 0x05,
+            _ => 0x03,
+        }
+    }
+
+    pub fn from_connack_v3(code: u8) -> Result<Self, Error> {
+        let reason_code = match code {
+            0x00 => ReasonCode::Success,
+            0x01 => ReasonCode::UnsupportedProtocolVersion,
+            0x02 => ReasonCode::ClientIdentifierNotValid,
+            0x03 => ReasonCode::ServerUnavailable,
+            0x04 => ReasonCode::BadUserNameOrPassword,
+            0x05 => ReasonCode::NotAuthorized,
+            _ => {
+                return Err(Error::MalformedPacket(format!(
+                    "CONNACK return code 0x{code:02X}"
+                )))
+            }
+        };
+        Ok(reason_code)
+    }
+
+    pub fn to_suback_v3(self) -> u8 {
+        match self {
+            ReasonCode::Success => 0x00,
+            ReasonCode::GrantedQoS1 => 0x01,
+            ReasonCode::GrantedQoS2 => 0x02,
+            _ => 0x80,
+        }
+    }
+
+    pub fn from_suback_v3(code: u8) -> Result<Self, Error> {
+        let reason_code = match code {
+            0x00 => ReasonCode::Success,
+            0x01 => ReasonCode::GrantedQoS1,
+            0x02 => ReasonCode::GrantedQoS2,
+            0x80 => ReasonCode::UnspecifiedError,
+            _ => {
+                return Err(Error::MalformedPacket(format!(
+                    "SUBACK return code 0x{code:02X}"
+                )))
+            }
+        };
+        Ok(reason_code)
     }
 }
 
-fn read_string(read: &mut Bytes) -> Result<String, Error> {
-    let len = read.get_u16() as usize;
+fn read_u8(read: &mut Bytes) -> Result<u8, Error> {
+    if read.remaining() < 1 {
+        return Err(Error::PacketTooShort);
+    }
+    Ok(read.get_u8())
+}
+fn read_u16(read: &mut Bytes) -> Result<u16, Error> {
+    if read.remaining() < 2 {
+        return Err(Error::PacketTooShort);
+    }
+    Ok(read.get_u16())
+}
+fn read_u32(read: &mut Bytes) -> Result<u32, Error> {
+    if read.remaining() < 4 {
+        return Err(Error::PacketTooShort);
+    }
+    Ok(read.get_u32())
+}
+// Two byte length followed by the data
+fn read_binary(read: &mut Bytes) -> Result<Bytes, Error> {
+    let len = read_u16(read)? as usize;
     if len > read.len() {
         return Err(Error::PacketTooShort);
     }
-    let bytes = read.split_to(len);
-    let str = String::from_utf8(bytes.to_vec())?;
+    Ok(read.split_to(len))
+}
+fn read_string(read: &mut Bytes) -> Result<String, Error> {
+    let str = String::from_utf8(read_binary(read)?.to_vec())?;
     Ok(str)
 }
 fn write_string(write: &mut BytesMut, str: &str) {
-    write.put_u16(str.len() as u16);
-    write.extend_from_slice(str.as_bytes());
+    write_binary(write, str.as_bytes());
+}
+fn write_binary(write: &mut BytesMut, data: &[u8]) {
+    write.put_u16(data.len() as u16);
+    write.extend_from_slice(data);
 }
 
+// Variable byte integer, at most four bytes long
 pub fn read_length(read: Iter<u8>) -> Result<(usize, usize), Error> {
     let mut len = 0;
     let mut bytes = 0;
@@ -228,6 +329,9 @@ pub fn read_length(read: Iter<u8>) -> Result<(usize, usize), Error> {
             done = true;
             break;
         }
+        if bytes == 4 {
+            return Err(Error::MalformedPacket("variable byte integer".to_owned()));
+        }
         mul += 7;
     }
 
@@ -237,6 +341,16 @@ pub fn read_length(read: Iter<u8>) -> Result<(usize, usize), Error> {
     Ok((len, bytes))
 }
 
+// Bytes a variable byte integer takes
+fn length_len(len: usize) -> usize {
+    match len {
+        0..=127 => 1,
+        128..=16_383 => 2,
+        16_384..=2_097_151 => 3,
+        _ => 4,
+    }
+}
+
 fn write_length(write: &mut BytesMut, mut len: usize) -> Result<(), Error> {
     if len > 268_435_455 {
         return Err(Error::PayloadTooLong);
@@ -248,7 +362,7 @@ fn write_length(write: &mut BytesMut, mut len: usize) -> Result<(), Error> {
             byte |= 0x80;
         }
         write.put_u8(byte);
-        if len <= 0 {
+        if len == 0 {
             return Ok(());
         }
     }
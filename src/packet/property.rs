@@ -0,0 +1,335 @@
+use crate::packet::*;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+// Properties each packet may carry, MQTT 5 section 2.2.2.2
+pub const CONNECT_PROPERTIES: &[Property] = &[
+    Property::SessionExpiryInterval,
+    Property::ReceiveMaximum,
+    Property::MaxPacketSize,
+    Property::TopicAliasMax,
+    Property::RequestResponseInfo,
+    Property::RequestProblemInfo,
+    Property::UserProperty,
+    Property::AuthMethod,
+    Property::AuthData,
+];
+pub const WILL_PROPERTIES: &[Property] = &[
+    Property::WillDelayInterval,
+    Property::PayloadFormatIndicator,
+    Property::MessageExpiryInterval,
+    Property::ContentType,
+    Property::ResponseTopic,
+    Property::CorrelationData,
+    Property::UserProperty,
+];
+pub const CONNACK_PROPERTIES: &[Property] = &[
+    Property::SessionExpiryInterval,
+    Property::ReceiveMaximum,
+    Property::MaximumQoS,
+    Property::RetainAvailable,
+    Property::MaxPacketSize,
+    Property::AssignedClientIdentifier,
+    Property::TopicAliasMax,
+    Property::ReasonString,
+    Property::UserProperty,
+    Property::WildcardSubAvailable,
+    Property::SubIdentifierAvailable,
+    Property::SharedSubAvailable,
+    Property::ServerKeepAlive,
+    Property::ResponseInfo,
+    Property::ServerReference,
+    Property::AuthMethod,
+    Property::AuthData,
+];
+pub const PUBLISH_PROPERTIES: &[Property] = &[
+    Property::PayloadFormatIndicator,
+    Property::MessageExpiryInterval,
+    Property::TopicAlias,
+    Property::ResponseTopic,
+    Property::CorrelationData,
+    Property::UserProperty,
+    Property::SubIdentifier,
+    Property::ContentType,
+];
+// PUBACK, PUBREC, PUBREL, PUBCOMP, SUBACK and UNSUBACK
+pub const ACK_PROPERTIES: &[Property] = &[Property::ReasonString, Property::UserProperty];
+pub const SUBSCRIBE_PROPERTIES: &[Property] = &[Property::SubIdentifier, Property::UserProperty];
+pub const UNSUBSCRIBE_PROPERTIES: &[Property] = &[Property::UserProperty];
+pub const DISCONNECT_PROPERTIES: &[Property] = &[
+    Property::SessionExpiryInterval,
+    Property::ReasonString,
+    Property::UserProperty,
+    Property::ServerReference,
+];
+pub const AUTH_PROPERTIES: &[Property] = &[
+    Property::AuthMethod,
+    Property::AuthData,
+    Property::ReasonString,
+    Property::UserProperty,
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PropertyValue {
+    Byte(u8),
+    TwoByteInteger(u16),
+    FourByteInteger(u32),
+    VariableByteInteger(u32),
+    String(String),
+    Binary(Bytes),
+    StringPair(String, String),
+}
+
+impl PropertyValue {
+    // The wire type is fixed by the property identifier
+    fn unpack(property: Property, read: &mut Bytes) -> Result<Self, Error> {
+        let value = match property {
+            Property::PayloadFormatIndicator
+            | Property::RequestProblemInfo
+            | Property::RequestResponseInfo
+            | Property::MaximumQoS
+            | Property::RetainAvailable
+            | Property::WildcardSubAvailable
+            | Property::SubIdentifierAvailable
+            | Property::SharedSubAvailable => Self::Byte(read_u8(read)?),
+
+            Property::ServerKeepAlive
+            | Property::ReceiveMaximum
+            | Property::TopicAliasMax
+            | Property::TopicAlias => Self::TwoByteInteger(read_u16(read)?),
+
+            Property::MessageExpiryInterval
+            | Property::SessionExpiryInterval
+            | Property::WillDelayInterval
+            | Property::MaxPacketSize => Self::FourByteInteger(read_u32(read)?),
+
+            Property::SubIdentifier => {
+                let (value, bytes) = read_length(read.iter())?;
+                read.advance(bytes);
+                Self::VariableByteInteger(value as u32)
+            }
+
+            Property::ContentType
+            | Property::ResponseTopic
+            | Property::AssignedClientIdentifier
+            | Property::AuthMethod
+            | Property::ResponseInfo
+            | Property::ServerReference
+            | Property::ReasonString => Self::String(read_string(read)?),
+
+            Property::CorrelationData | Property::AuthData => Self::Binary(read_binary(read)?),
+
+            Property::UserProperty => {
+                let k = read_string(read)?;
+                let v = read_string(read)?;
+                Self::StringPair(k, v)
+            }
+        };
+        Ok(value)
+    }
+
+    fn pack(&self, write: &mut BytesMut) -> Result<(), Error> {
+        match self {
+            Self::Byte(v) => write.put_u8(*v),
+            Self::TwoByteInteger(v) => write.put_u16(*v),
+            Self::FourByteInteger(v) => write.put_u32(*v),
+            Self::VariableByteInteger(v) => write_length(write, *v as usize)?,
+            Self::String(v) => write_string(write, v),
+            Self::Binary(v) => write_binary(write, v),
+            Self::StringPair(k, v) => {
+                write_string(write, k);
+                write_string(write, v);
+            }
+        }
+        Ok(())
+    }
+
+    // Encoded length without the identifier byte
+    fn len(&self) -> usize {
+        match self {
+            Self::Byte(_) => 1,
+            Self::TwoByteInteger(_) => 2,
+            Self::FourByteInteger(_) => 4,
+            Self::VariableByteInteger(v) => length_len(*v as usize),
+            Self::String(v) => 2 + v.len(),
+            Self::Binary(v) => 2 + v.len(),
+            Self::StringPair(k, v) => 4 + k.len() + v.len(),
+        }
+    }
+}
+
+// Properties in wire order, repeated entries kept
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Properties(Vec<(Property, PropertyValue)>);
+
+// Moves the values out, so typed properties are built without copies
+impl IntoIterator for Properties {
+    type Item = (Property, PropertyValue);
+    type IntoIter = std::vec::IntoIter<(Property, PropertyValue)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl Properties {
+    pub fn new() -> Self {
+        Self {
+            ..Default::default()
+        }
+    }
+
+    pub fn unpack(read: &mut Bytes, allowed: &[Property]) -> Result<Option<Self>, Error> {
+        let (len, bytes) = read_length(read.iter())?;
+        read.advance(bytes);
+
+        if len > read.len() {
+            return Err(Error::PacketTooShort);
+        }
+        if len == 0 {
+            return Ok(None);
+        }
+
+        let mut read = read.split_to(len);
+        let mut props = Self::new();
+        let mut seen = 0u64;
+
+        while !read.is_empty() {
+            let identifier = read_u8(&mut read)?;
+            let property = Property::try_from(identifier)?;
+            if !allowed.contains(&property) {
+                return Err(Error::ProtocolError(format!(
+                    "unexpected property 0x{identifier:02X}"
+                )));
+            }
+
+            // Only user properties and subscription identifiers may repeat
+            if property != Property::UserProperty && property != Property::SubIdentifier {
+                let bit = 1 << (identifier & 0x3F);
+                if seen & bit > 0 {
+                    return Err(Error::ProtocolError(format!(
+                        "duplicate property 0x{identifier:02X}"
+                    )));
+                }
+                seen |= bit;
+            }
+
+            let value = PropertyValue::unpack(property, &mut read)?;
+            props.0.push((property, value));
+        }
+        Ok(Some(props))
+    }
+
+    pub fn pack(&self, write: &mut BytesMut) -> Result<(), Error> {
+        write.reserve(self.len());
+        for (property, value) in self.0.iter() {
+            write.put_u8(*property as u8);
+            value.pack(write)?;
+        }
+        Ok(())
+    }
+
+    // Encoded length without the leading property length
+    pub fn len(&self) -> usize {
+        self.0.iter().map(|(_, value)| 1 + value.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(Property, PropertyValue)> {
+        self.0.iter()
+    }
+
+    pub fn get(&self, property: Property) -> Option<&PropertyValue> {
+        self.get_all(property).next()
+    }
+
+    pub fn get_all(&self, property: Property) -> impl Iterator<Item = &PropertyValue> {
+        self.0
+            .iter()
+            .filter(move |(p, _)| *p == property)
+            .map(|(_, value)| value)
+    }
+
+    pub fn push(&mut self, property: Property, value: PropertyValue) {
+        self.0.push((property, value));
+    }
+
+    // Skips absent optional properties
+    pub fn set(&mut self, property: Property, value: Option<PropertyValue>) {
+        if let Some(value) = value {
+            self.push(property, value);
+        }
+    }
+
+    pub fn get_u8(&self, property: Property) -> Option<u8> {
+        match self.get(property) {
+            Some(PropertyValue::Byte(v)) => Some(*v),
+            _ => None,
+        }
+    }
+
+    pub fn get_u16(&self, property: Property) -> Option<u16> {
+        match self.get(property) {
+            Some(PropertyValue::TwoByteInteger(v)) => Some(*v),
+            _ => None,
+        }
+    }
+
+    pub fn get_u32(&self, property: Property) -> Option<u32> {
+        match self.get(property) {
+            Some(PropertyValue::FourByteInteger(v) | PropertyValue::VariableByteInteger(v)) => {
+                Some(*v)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn get_string(&self, property: Property) -> Option<&str> {
+        match self.get(property) {
+            Some(PropertyValue::String(v)) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn get_binary(&self, property: Property) -> Option<&Bytes> {
+        match self.get(property) {
+            Some(PropertyValue::Binary(v)) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn sub_identifier(&self) -> Vec<u32> {
+        self.get_all(Property::SubIdentifier)
+            .filter_map(|value| match value {
+                PropertyValue::VariableByteInteger(v) => Some(*v),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn user_property(&self) -> Vec<(&str, &str)> {
+        self.get_all(Property::UserProperty)
+            .filter_map(|value| match value {
+                PropertyValue::StringPair(k, v) => Some((k.as_str(), v.as_str())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn set_sub_identifier(&mut self, sub_identifier: Vec<u32>) {
+        for v in sub_identifier {
+            self.push(
+                Property::SubIdentifier,
+                PropertyValue::VariableByteInteger(v),
+            );
+        }
+    }
+
+    pub fn set_user_property(&mut self, user_property: Vec<(String, String)>) {
+        for (k, v) in user_property {
+            self.push(Property::UserProperty, PropertyValue::StringPair(k, v));
+        }
+    }
+}
@@ -1,5 +1,5 @@
 use crate::packet::*;
-use bytes::{BufMut, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
 
 #[derive(Debug, Default, Clone)]
 pub struct PubComp {
@@ -14,11 +14,27 @@ impl PubComp {
             ..Default::default()
         }
     }
+    pub fn unpack(mut read: Bytes, version: Version) -> Result<Self, Error> {
+        let mut pubcomp = Self::new();
+        pubcomp.packet_id = read_u16(&mut read)?;
+        if read.is_empty() {
+            return Ok(pubcomp);
+        }
+
+        if version == Version::V5 {
+            pubcomp.reason_code = ReasonCode::try_from(read_u8(&mut read)?)?;
+            if read.is_empty() {
+                return Ok(pubcomp);
+            }
+            pubcomp.properties = PubCompProperties::unpack(&mut read)?;
+        }
+        Ok(pubcomp)
+    }
     pub fn pack(self, write: &mut BytesMut, version: Version) -> Result<(), Error> {
         let mut props_len = 0;
         let mut props_buf = BytesMut::with_capacity(512);
         if let Some(props) = self.properties {
-            props.pack(&mut props_buf);
+            props.pack(&mut props_buf)?;
             props_len = props_buf.len();
         }
 
@@ -49,16 +65,43 @@ impl PubCompProperties {
             ..Default::default()
         }
     }
-    pub fn pack(self, write: &mut BytesMut) {
-        if let Some(reason_string) = self.reason_string {
-            write.put_u8(Property::ReasonString as u8);
-            write_string(write, &reason_string);
-        }
 
-        for (k, v) in self.user_property.iter() {
-            write.put_u8(Property::UserProperty as u8);
-            write_string(write, k);
-            write_string(write, v);
+    pub fn unpack(read: &mut Bytes) -> Result<Option<Self>, Error> {
+        let props = Properties::unpack(read, ACK_PROPERTIES)?;
+        Ok(props.map(Self::from))
+    }
+
+    pub fn pack(self, write: &mut BytesMut) -> Result<(), Error> {
+        Properties::from(self).pack(write)
+    }
+}
+
+impl From<Properties> for PubCompProperties {
+    fn from(props: Properties) -> Self {
+        let mut prop = Self::new();
+        for (property, value) in props {
+            match (property, value) {
+                (Property::ReasonString, PropertyValue::String(v)) => {
+                    prop.reason_string = Some(v);
+                }
+                (Property::UserProperty, PropertyValue::StringPair(k, v)) => {
+                    prop.user_property.push((k, v));
+                }
+                _ => {}
+            }
         }
+        prop
+    }
+}
+
+impl From<PubCompProperties> for Properties {
+    fn from(prop: PubCompProperties) -> Self {
+        let mut props = Properties::new();
+        props.set(
+            Property::ReasonString,
+            prop.reason_string.map(PropertyValue::String),
+        );
+        props.set_user_property(prop.user_property);
+        props
     }
 }
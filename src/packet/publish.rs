@@ -1,5 +1,5 @@
 use crate::packet::*;
-use bytes::{Buf, Bytes};
+use bytes::{BufMut, Bytes, BytesMut};
 
 #[derive(Debug, Default, Clone)]
 pub struct Publish {
@@ -9,7 +9,7 @@ pub struct Publish {
     pub topic_name: String,
     pub packet_id: u16,
     pub properties: Option<PublishProperties>,
-    pub payload: Vec<u8>,
+    pub payload: Bytes,
 }
 
 impl Publish {
@@ -26,13 +26,19 @@ impl Publish {
         publish.dup = flags >> 3 > 0;
         publish.qos = QoS::try_from((flags >> 1) & 0x03)?;
         publish.retain = flags & 0x01 > 0;
+        if publish.dup && publish.qos == QoS::AtMostOnce {
+            return Err(Error::MalformedPacket("DUP set on QoS 0".to_owned()));
+        }
 
         // Topic Name
         publish.topic_name = read_string(&mut read)?;
 
         // Packet ID
         if publish.qos > QoS::AtMostOnce {
-            publish.packet_id = read.get_u16();
+            publish.packet_id = read_u16(&mut read)?;
+            if publish.packet_id == 0 {
+                return Err(Error::ProtocolError("packet id 0".to_owned()));
+            }
         }
 
         // Properties
@@ -41,9 +47,51 @@ impl Publish {
         }
 
         // Payload
-        publish.payload = read.to_vec();
+        publish.payload = read;
         Ok(publish)
     }
+
+    pub fn pack(self, write: &mut BytesMut, version: Version) -> Result<(), Error> {
+        // Fixed Header
+        let mut byte1 = (PacketType::Publish as u8) << 4;
+        byte1 |= (self.dup as u8) << 3;
+        byte1 |= (self.qos as u8) << 1;
+        byte1 |= self.retain as u8;
+
+        // Remaining length worked out first, so the payload is copied only once
+        let props = match version {
+            Version::V5 => Some(Properties::from(self.properties.unwrap_or_default())),
+            _ => None,
+        };
+        let mut len = 2 + self.topic_name.len() + self.payload.len();
+        if self.qos > QoS::AtMostOnce {
+            len += 2;
+        }
+        if let Some(props) = &props {
+            len += length_len(props.len()) + props.len();
+        }
+        write.reserve(5 + len);
+        write.put_u8(byte1);
+        write_length(write, len)?;
+
+        // Topic Name
+        write_string(write, &self.topic_name);
+
+        // Packet ID
+        if self.qos > QoS::AtMostOnce {
+            write.put_u16(self.packet_id);
+        }
+
+        // Properties
+        if let Some(props) = props {
+            write_length(write, props.len())?;
+            props.pack(write)?;
+        }
+
+        // Payload
+        write.put_slice(&self.payload);
+        Ok(())
+    }
 }
 
 #[derive(Debug, Default, Clone)]
@@ -52,7 +100,7 @@ pub struct PublishProperties {
     pub message_expiry_interval: Option<u32>,
     pub content_type: Option<String>,
     pub response_topic: Option<String>,
-    pub correlation_data: Option<Vec<u8>>,
+    pub correlation_data: Option<Bytes>,
     pub sub_identifier: Vec<u32>,
     pub topic_alias: Option<u16>,
     pub user_property: Vec<(String, String)>,
@@ -66,60 +114,81 @@ impl PublishProperties {
     }
 
     pub fn unpack(read: &mut Bytes) -> Result<Option<Self>, Error> {
-        let (len, bytes) = read_length(read.iter())?;
-        read.advance(bytes);
+        let props = Properties::unpack(read, PUBLISH_PROPERTIES)?;
+        Ok(props.map(Self::from))
+    }
 
-        if len == 0 {
-            return Ok(None);
-        }
+    pub fn pack(self, write: &mut BytesMut) -> Result<(), Error> {
+        Properties::from(self).pack(write)
+    }
+}
 
-        let mut read = read.split_to(len);
+impl From<Properties> for PublishProperties {
+    fn from(props: Properties) -> Self {
         let mut prop = Self::new();
-
-        loop {
-            if read.len() == 0 {
-                return Ok(Some(prop));
-            }
-            let identifier = read.get_u8();
-            match Property::try_from(identifier)? {
-                Property::PayloadFormatIndicator => {
-                    prop.payload_format_indicator = Some(read.get_u8());
+        for (property, value) in props {
+            match (property, value) {
+                (Property::PayloadFormatIndicator, PropertyValue::Byte(v)) => {
+                    prop.payload_format_indicator = Some(v);
                 }
-
-                Property::MessageExpiryInterval => {
-                    prop.message_expiry_interval = Some(read.get_u32());
+                (Property::MessageExpiryInterval, PropertyValue::FourByteInteger(v)) => {
+                    prop.message_expiry_interval = Some(v);
                 }
-
-                Property::ContentType => {
-                    prop.content_type = Some(read_string(&mut read)?);
+                (Property::ContentType, PropertyValue::String(v)) => {
+                    prop.content_type = Some(v);
                 }
-
-                Property::ResponseTopic => {
-                    prop.response_topic = Some(read_string(&mut read)?);
+                (Property::ResponseTopic, PropertyValue::String(v)) => {
+                    prop.response_topic = Some(v);
                 }
-                Property::CorrelationData => {
-                    let len = read.get_u16() as usize;
-                    let read = read.split_to(len);
-                    prop.correlation_data = Some(read.to_vec())
+                (Property::CorrelationData, PropertyValue::Binary(v)) => {
+                    prop.correlation_data = Some(v);
                 }
-
-                Property::SubIdentifier => {
-                    let (len, bytes) = read_length(read.iter())?;
-                    read.advance(bytes);
-                    prop.sub_identifier.push(len as u32);
+                (Property::SubIdentifier, PropertyValue::VariableByteInteger(v)) => {
+                    prop.sub_identifier.push(v);
                 }
-
-                Property::TopicAlias => {
-                    prop.topic_alias = Some(read.get_u16());
+                (Property::TopicAlias, PropertyValue::TwoByteInteger(v)) => {
+                    prop.topic_alias = Some(v);
                 }
-
-                Property::UserProperty => {
-                    let k = read_string(&mut read)?;
-                    let v = read_string(&mut read)?;
+                (Property::UserProperty, PropertyValue::StringPair(k, v)) => {
                     prop.user_property.push((k, v));
                 }
-                _ => unreachable!(),
+                _ => {}
             }
         }
+        prop
+    }
+}
+
+impl From<PublishProperties> for Properties {
+    fn from(prop: PublishProperties) -> Self {
+        let mut props = Properties::new();
+        props.set(
+            Property::PayloadFormatIndicator,
+            prop.payload_format_indicator.map(PropertyValue::Byte),
+        );
+        props.set(
+            Property::MessageExpiryInterval,
+            prop.message_expiry_interval
+                .map(PropertyValue::FourByteInteger),
+        );
+        props.set(
+            Property::ContentType,
+            prop.content_type.map(PropertyValue::String),
+        );
+        props.set(
+            Property::ResponseTopic,
+            prop.response_topic.map(PropertyValue::String),
+        );
+        props.set(
+            Property::CorrelationData,
+            prop.correlation_data.map(PropertyValue::Binary),
+        );
+        props.set_sub_identifier(prop.sub_identifier);
+        props.set(
+            Property::TopicAlias,
+            prop.topic_alias.map(PropertyValue::TwoByteInteger),
+        );
+        props.set_user_property(prop.user_property);
+        props
     }
 }
@@ -16,14 +16,14 @@ impl PubRel {
     }
     pub fn unpack(mut read: Bytes, version: Version) -> Result<Self, Error> {
         let mut pubrel = Self::new();
-        pubrel.packet_id = read.get_u16();
-        if read.len() == 0 {
+        pubrel.packet_id = read_u16(&mut read)?;
+        if read.is_empty() {
             return Ok(pubrel);
         }
 
         if version == Version::V5 {
-            pubrel.reason_code = ReasonCode::try_from(read.get_u8())?;
-            if read.len() == 0 {
+            pubrel.reason_code = ReasonCode::try_from(read_u8(&mut read)?)?;
+            if read.is_empty() {
                 return Ok(pubrel);
             }
             pubrel.properties = PubRelProperties::unpack(&mut read)?;
@@ -34,7 +34,7 @@ impl PubRel {
         let mut props_len = 0;
         let mut props_buf = BytesMut::with_capacity(512);
         if let Some(props) = self.properties {
-            props.pack(&mut props_buf);
+            props.pack(&mut props_buf)?;
             props_len = props_buf.len();
         }
 
@@ -46,7 +46,7 @@ impl PubRel {
             buf.put(props_buf.freeze());
         }
 
-        write.put_u8((PacketType::PubRec as u8) << 4);
+        write.put_u8((PacketType::PubRel as u8) << 4 | 0x02);
         write_length(write, buf.len())?;
         write.put(buf.freeze());
         Ok(())
@@ -65,46 +65,43 @@ impl PubRelProperties {
             ..Default::default()
         }
     }
+
     pub fn unpack(read: &mut Bytes) -> Result<Option<Self>, Error> {
-        let (len, bytes) = read_length(read.iter())?;
-        read.advance(bytes);
+        let props = Properties::unpack(read, ACK_PROPERTIES)?;
+        Ok(props.map(Self::from))
+    }
 
-        if len == 0 {
-            return Ok(None);
-        }
+    pub fn pack(self, write: &mut BytesMut) -> Result<(), Error> {
+        Properties::from(self).pack(write)
+    }
+}
 
-        let mut read = read.split_to(len);
+impl From<Properties> for PubRelProperties {
+    fn from(props: Properties) -> Self {
         let mut prop = Self::new();
-
-        loop {
-            if read.len() == 0 {
-                return Ok(Some(prop));
-            }
-            let identifier = read.get_u8();
-            match Property::try_from(identifier)? {
-                Property::ReasonString => {
-                    prop.reason_string = Some(read_string(&mut read)?);
+        for (property, value) in props {
+            match (property, value) {
+                (Property::ReasonString, PropertyValue::String(v)) => {
+                    prop.reason_string = Some(v);
                 }
-
-                Property::UserProperty => {
-                    let k = read_string(&mut read)?;
-                    let v = read_string(&mut read)?;
+                (Property::UserProperty, PropertyValue::StringPair(k, v)) => {
                     prop.user_property.push((k, v));
                 }
-                _ => unreachable!(),
+                _ => {}
             }
         }
+        prop
     }
-    pub fn pack(self, write: &mut BytesMut) {
-        if let Some(reason_string) = self.reason_string {
-            write.put_u8(Property::ReasonString as u8);
-            write_string(write, &reason_string);
-        }
+}
 
-        for (k, v) in self.user_property.iter() {
-            write.put_u8(Property::UserProperty as u8);
-            write_string(write, k);
-            write_string(write, v);
-        }
+impl From<PubRelProperties> for Properties {
+    fn from(prop: PubRelProperties) -> Self {
+        let mut props = Properties::new();
+        props.set(
+            Property::ReasonString,
+            prop.reason_string.map(PropertyValue::String),
+        );
+        props.set_user_property(prop.user_property);
+        props
     }
 }
@@ -1,5 +1,5 @@
 use crate::packet::*;
-use bytes::{Buf, Bytes};
+use bytes::{BufMut, Bytes, BytesMut};
 
 #[derive(Debug, Default, Clone)]
 pub struct Subscribe {
@@ -16,18 +16,14 @@ pub struct Subscription {
     pub qos: QoS,
 }
 
-#[derive(Debug, TryFromPrimitive, Clone)]
+#[derive(Debug, Default, PartialEq, Copy, Clone, TryFromPrimitive)]
 #[repr(u8)]
-enum RetainHandling {
+pub enum RetainHandling {
+    #[default]
     Sub = 0,
     NewSub,
     Never,
 }
-impl Default for RetainHandling {
-    fn default() -> Self {
-        Self::Sub
-    }
-}
 impl Subscribe {
     pub fn new() -> Self {
         Self {
@@ -38,7 +34,10 @@ impl Subscribe {
         let mut sub = Self::new();
 
         // Packet ID
-        sub.packet_id = read.get_u16();
+        sub.packet_id = read_u16(&mut read)?;
+        if sub.packet_id == 0 {
+            return Err(Error::ProtocolError("packet id 0".to_owned()));
+        }
 
         // Properties
         if version == Version::V5 {
@@ -46,10 +45,19 @@ impl Subscribe {
         }
 
         // Payload
-        while read.len() > 0 {
+        while !read.is_empty() {
             let topic = read_string(&mut read)?;
-            let options = read.get_u8();
-            let retain_handling = RetainHandling::try_from(options >> 4 & 0x03).unwrap();
+            let options = read_u8(&mut read)?;
+            // Only the QoS bits are in use before v5
+            let reserved = match version {
+                Version::V5 => 0xC0,
+                _ => 0xFC,
+            };
+            if options & reserved > 0 {
+                return Err(Error::MalformedPacket("subscription options".to_owned()));
+            }
+            let retain_handling = RetainHandling::try_from(options >> 4 & 0x03)
+                .map_err(|_| Error::ProtocolError("retain handling".to_owned()))?;
             let retain_as_published = options & 0x08 > 0;
             let no_local = options & 0x04 > 0;
             let qos = QoS::try_from(options & 0x03)?;
@@ -61,8 +69,44 @@ impl Subscribe {
                 qos,
             })
         }
+        if sub.payload.is_empty() {
+            return Err(Error::ProtocolError("no topic filters".to_owned()));
+        }
         Ok(sub)
     }
+
+    pub fn pack(self, write: &mut BytesMut, version: Version) -> Result<(), Error> {
+        // Packet ID
+        let mut buf = BytesMut::with_capacity(512);
+        buf.put_u16(self.packet_id);
+
+        // Properties
+        if version == Version::V5 {
+            let mut props_buf = BytesMut::with_capacity(512);
+            if let Some(props) = self.properties {
+                props.pack(&mut props_buf)?;
+            }
+            write_length(&mut buf, props_buf.len())?;
+            buf.put(props_buf.freeze());
+        }
+
+        // Payload, v3 only has the QoS in the options
+        for subscription in self.payload {
+            write_string(&mut buf, &subscription.topic);
+            let mut options = subscription.qos as u8;
+            if version == Version::V5 {
+                options |= (subscription.no_local as u8) << 2;
+                options |= (subscription.retain_as_published as u8) << 3;
+                options |= (subscription.retain_handling as u8) << 4;
+            }
+            buf.put_u8(options);
+        }
+
+        write.put_u8((PacketType::Subscribe as u8) << 4 | 0x02);
+        write_length(write, buf.len())?;
+        write.put(buf.freeze());
+        Ok(())
+    }
 }
 
 #[derive(Debug, Default, Clone)]
@@ -79,35 +123,44 @@ impl SubscribeProperties {
     }
 
     pub fn unpack(read: &mut Bytes) -> Result<Option<Self>, Error> {
-        let (len, bytes) = read_length(read.iter())?;
-        read.advance(bytes);
-
-        if len == 0 {
-            return Ok(None);
+        let prop = Properties::unpack(read, SUBSCRIBE_PROPERTIES)?.map(Self::from);
+        if let Some(prop) = &prop {
+            // At most one non-zero subscription identifier
+            if prop.sub_identifier.len() > 1 || prop.sub_identifier.contains(&0) {
+                return Err(Error::ProtocolError("subscription identifier".to_owned()));
+            }
         }
+        Ok(prop)
+    }
 
-        let mut read = read.split_to(len);
-        let mut prop = Self::new();
+    pub fn pack(self, write: &mut BytesMut) -> Result<(), Error> {
+        Properties::from(self).pack(write)
+    }
+}
 
-        loop {
-            if read.len() == 0 {
-                return Ok(Some(prop));
-            }
-            let identifier = read.get_u8();
-            match Property::try_from(identifier)? {
-                Property::SubIdentifier => {
-                    let (len, bytes) = read_length(read.iter())?;
-                    read.advance(bytes);
-                    prop.sub_identifier.push(len as u32);
+impl From<Properties> for SubscribeProperties {
+    fn from(props: Properties) -> Self {
+        let mut prop = Self::new();
+        for (property, value) in props {
+            match (property, value) {
+                (Property::SubIdentifier, PropertyValue::VariableByteInteger(v)) => {
+                    prop.sub_identifier.push(v);
                 }
-
-                Property::UserProperty => {
-                    let k = read_string(&mut read)?;
-                    let v = read_string(&mut read)?;
+                (Property::UserProperty, PropertyValue::StringPair(k, v)) => {
                     prop.user_property.push((k, v));
                 }
-                _ => unreachable!(),
+                _ => {}
             }
         }
+        prop
+    }
+}
+
+impl From<SubscribeProperties> for Properties {
+    fn from(prop: SubscribeProperties) -> Self {
+        let mut props = Properties::new();
+        props.set_sub_identifier(prop.sub_identifier);
+        props.set_user_property(prop.user_property);
+        props
     }
 }
@@ -1,5 +1,5 @@
 use crate::packet::*;
-use bytes::{BufMut, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
 
 #[derive(Debug, Default, Clone)]
 pub struct UnsubAck {
@@ -15,12 +15,30 @@ impl UnsubAck {
         }
     }
 
+    pub fn unpack(mut read: Bytes, version: Version) -> Result<Self, Error> {
+        let mut unsuback = Self::new();
+
+        // Packet ID
+        unsuback.packet_id = read_u16(&mut read)?;
+
+        // Properties and payload, v3 UNSUBACK has neither
+        if version == Version::V5 {
+            unsuback.properties = UnsubAckProperties::unpack(&mut read)?;
+            while !read.is_empty() {
+                unsuback
+                    .payload
+                    .push(ReasonCode::try_from(read_u8(&mut read)?)?);
+            }
+        }
+        Ok(unsuback)
+    }
+
     pub fn pack(self, write: &mut BytesMut, version: Version) -> Result<(), Error> {
         // Properties
         let mut props_len = 0;
         let mut props_buf = BytesMut::with_capacity(512);
         if let Some(props) = self.properties {
-            props.pack(&mut props_buf);
+            props.pack(&mut props_buf)?;
             props_len = props_buf.len();
         }
 
@@ -30,9 +48,11 @@ impl UnsubAck {
         if version == Version::V5 {
             write_length(&mut buf, props_len)?;
             buf.put(props_buf.freeze());
+
+            // v3 UNSUBACK has no payload
+            let payload: Vec<u8> = self.payload.iter().map(|&rc| rc as u8).collect();
+            buf.put_slice(&payload);
         }
-        let payload: Vec<u8> = self.payload.iter().map(|&rc| rc as u8).collect();
-        buf.put_slice(&payload);
 
         write.put_u8((PacketType::UnsubAck as u8) << 4);
         write_length(write, buf.len())?;
@@ -53,16 +73,43 @@ impl UnsubAckProperties {
             ..Default::default()
         }
     }
-    pub fn pack(self, write: &mut BytesMut) {
-        if let Some(reason_string) = self.reason_string {
-            write.put_u8(Property::ReasonString as u8);
-            write_string(write, &reason_string);
-        }
 
-        for (k, v) in self.user_property.iter() {
-            write.put_u8(Property::UserProperty as u8);
-            write_string(write, k);
-            write_string(write, v);
+    pub fn unpack(read: &mut Bytes) -> Result<Option<Self>, Error> {
+        let props = Properties::unpack(read, ACK_PROPERTIES)?;
+        Ok(props.map(Self::from))
+    }
+
+    pub fn pack(self, write: &mut BytesMut) -> Result<(), Error> {
+        Properties::from(self).pack(write)
+    }
+}
+
+impl From<Properties> for UnsubAckProperties {
+    fn from(props: Properties) -> Self {
+        let mut prop = Self::new();
+        for (property, value) in props {
+            match (property, value) {
+                (Property::ReasonString, PropertyValue::String(v)) => {
+                    prop.reason_string = Some(v);
+                }
+                (Property::UserProperty, PropertyValue::StringPair(k, v)) => {
+                    prop.user_property.push((k, v));
+                }
+                _ => {}
+            }
         }
+        prop
+    }
+}
+
+impl From<UnsubAckProperties> for Properties {
+    fn from(prop: UnsubAckProperties) -> Self {
+        let mut props = Properties::new();
+        props.set(
+            Property::ReasonString,
+            prop.reason_string.map(PropertyValue::String),
+        );
+        props.set_user_property(prop.user_property);
+        props
     }
 }
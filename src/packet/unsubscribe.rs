@@ -1,5 +1,5 @@
 use crate::packet::*;
-use bytes::{Buf, Bytes};
+use bytes::{BufMut, Bytes, BytesMut};
 
 #[derive(Debug, Default, Clone)]
 pub struct Unsubscribe {
@@ -18,7 +18,10 @@ impl Unsubscribe {
         let mut unsub = Self::new();
 
         // Packet ID
-        unsub.packet_id = read.get_u16();
+        unsub.packet_id = read_u16(&mut read)?;
+        if unsub.packet_id == 0 {
+            return Err(Error::ProtocolError("packet id 0".to_owned()));
+        }
 
         // Properties
         if version == Version::V5 {
@@ -26,17 +29,45 @@ impl Unsubscribe {
         }
 
         // Payload
-        while read.len() > 0 {
+        while !read.is_empty() {
             let topic = read_string(&mut read)?;
             unsub.payload.push(topic);
         }
+        if unsub.payload.is_empty() {
+            return Err(Error::ProtocolError("no topic filters".to_owned()));
+        }
         Ok(unsub)
     }
+
+    pub fn pack(self, write: &mut BytesMut, version: Version) -> Result<(), Error> {
+        // Packet ID
+        let mut buf = BytesMut::with_capacity(512);
+        buf.put_u16(self.packet_id);
+
+        // Properties
+        if version == Version::V5 {
+            let mut props_buf = BytesMut::with_capacity(512);
+            if let Some(props) = self.properties {
+                props.pack(&mut props_buf)?;
+            }
+            write_length(&mut buf, props_buf.len())?;
+            buf.put(props_buf.freeze());
+        }
+
+        // Payload
+        for topic in self.payload {
+            write_string(&mut buf, &topic);
+        }
+
+        write.put_u8((PacketType::Unsubscribe as u8) << 4 | 0x02);
+        write_length(write, buf.len())?;
+        write.put(buf.freeze());
+        Ok(())
+    }
 }
 
 #[derive(Debug, Default, Clone)]
 pub struct UnsubscribeProperties {
-    pub sub_identifier: Vec<u32>,
     pub user_property: Vec<(String, String)>,
 }
 
@@ -48,35 +79,31 @@ impl UnsubscribeProperties {
     }
 
     pub fn unpack(read: &mut Bytes) -> Result<Option<Self>, Error> {
-        let (len, bytes) = read_length(read.iter())?;
-        read.advance(bytes);
+        let props = Properties::unpack(read, UNSUBSCRIBE_PROPERTIES)?;
+        Ok(props.map(Self::from))
+    }
 
-        if len == 0 {
-            return Ok(None);
-        }
+    pub fn pack(self, write: &mut BytesMut) -> Result<(), Error> {
+        Properties::from(self).pack(write)
+    }
+}
 
-        let mut read = read.split_to(len);
+impl From<Properties> for UnsubscribeProperties {
+    fn from(props: Properties) -> Self {
         let mut prop = Self::new();
-
-        loop {
-            if read.len() == 0 {
-                return Ok(Some(prop));
-            }
-            let identifier = read.get_u8();
-            match Property::try_from(identifier)? {
-                Property::SubIdentifier => {
-                    let (len, bytes) = read_length(read.iter())?;
-                    read.advance(bytes);
-                    prop.sub_identifier.push(len as u32);
-                }
-
-                Property::UserProperty => {
-                    let k = read_string(&mut read)?;
-                    let v = read_string(&mut read)?;
-                    prop.user_property.push((k, v));
-                }
-                _ => unreachable!(),
+        for (_, value) in props {
+            if let PropertyValue::StringPair(k, v) = value {
+                prop.user_property.push((k, v));
             }
         }
+        prop
+    }
+}
+
+impl From<UnsubscribeProperties> for Properties {
+    fn from(prop: UnsubscribeProperties) -> Self {
+        let mut props = Properties::new();
+        props.set_user_property(prop.user_property);
+        props
     }
 }
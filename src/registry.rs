@@ -0,0 +1,54 @@
+use crate::*;
+use std::collections::HashMap;
+use std::sync::RwLock;
+use tokio::sync::mpsc::UnboundedSender;
+
+// Clients currently online, keyed by client id
+pub struct Registry(pub(crate) RwLock<HashMap<String, Client>>);
+
+#[derive(Debug, Clone)]
+pub struct Client {
+    pub client_id: String,
+    pub version: Version,
+    pub keepalive: u16,
+    pub(crate) tx: UnboundedSender<Message>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Registry(RwLock::new(HashMap::new()))
+    }
+
+    pub fn contains(&self, client_id: &str) -> bool {
+        self.0.read().unwrap().contains_key(client_id)
+    }
+
+    pub fn get(&self, client_id: &str) -> Option<Client> {
+        self.0.read().unwrap().get(client_id).cloned()
+    }
+
+    pub fn clients(&self) -> Vec<Client> {
+        self.0.read().unwrap().values().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.read().unwrap().is_empty()
+    }
+
+    // Disconnects an online client, the reason code is only sent to v5 clients
+    pub fn kick(&self, client_id: &str, reason_code: ReasonCode) -> bool {
+        match self.0.read().unwrap().get(client_id) {
+            Some(client) => client.tx.send(Message::Kick(reason_code)).is_ok(),
+            None => false,
+        }
+    }
+}
+impl Default for Registry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
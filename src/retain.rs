@@ -0,0 +1,80 @@
+use crate::*;
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+// Last retained message per topic
+pub struct Retain(RwLock<HashMap<String, Envelope>>);
+
+impl Retain {
+    pub fn new() -> Self {
+        Retain(RwLock::new(HashMap::new()))
+    }
+
+    // A retained message with an empty payload deletes the topic
+    pub fn insert(&self, publish: &Publish) {
+        let mut retained = self.0.write().unwrap();
+        if publish.payload.is_empty() {
+            retained.remove(&publish.topic_name);
+            return;
+        }
+        let mut message = publish.clone();
+        // Kept indefinitely, so it must not pin the read buffer
+        message.payload = Bytes::copy_from_slice(&publish.payload);
+        message.dup = false;
+        message.packet_id = 0;
+        retained.insert(message.topic_name.clone(), Envelope::new(message));
+    }
+
+    // Message expiry intervals are rewritten to the remaining lifetime
+    pub fn get(&self, topic: &str) -> Option<Publish> {
+        let envelope = self.0.read().unwrap().get(topic).cloned()?;
+        let publish = envelope.open();
+        if publish.is_none() {
+            self.prune(vec![topic.to_owned()]);
+        }
+        publish
+    }
+
+    pub fn matches(&self, filter: &str) -> Vec<Publish> {
+        let mut matched = Vec::new();
+        let mut expired = Vec::new();
+        for envelope in self.0.read().unwrap().values() {
+            if !topic::matches(filter, &envelope.publish.topic_name) {
+                continue;
+            }
+            match envelope.clone().open() {
+                Some(publish) => matched.push(publish),
+                None => expired.push(envelope.publish.topic_name.clone()),
+            }
+        }
+        if !expired.is_empty() {
+            self.prune(expired);
+        }
+        matched
+    }
+
+    // Expired messages are dropped when a lookup runs into them
+    fn prune(&self, topics: Vec<String>) {
+        let mut retained = self.0.write().unwrap();
+        for topic in topics {
+            // Replaced by a newer message since the lookup
+            if retained.get(&topic).is_some_and(Envelope::expired) {
+                retained.remove(&topic);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.read().unwrap().is_empty()
+    }
+}
+impl Default for Retain {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,492 @@
+use crate::*;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::task;
+use tokio::time::{sleep, Instant};
+
+// Subscription index shared by every link
+pub struct Router {
+    tree: RwLock<Node>,
+    clients: Arc<Registry>,
+    retain: Arc<Retain>,
+    sessions: Mutex<HashMap<String, Session>>,
+    wills: Mutex<HashMap<String, (Instant, Publish)>>,
+    shares: Shares,
+    next_client_id: AtomicU64,
+}
+
+// Matching subscription per client along with every matching identifier
+type Matched = HashMap<String, (Subscription, Vec<u32>)>;
+
+// Shared subscription groups matching a topic, keyed by $share filter
+type Groups = HashMap<String, Vec<(String, Entry)>>;
+
+#[derive(Default)]
+struct Node {
+    children: HashMap<String, Node>,
+    subscribers: HashMap<String, Entry>,
+    // Members of each shared subscription group, keyed by group name
+    shared: HashMap<String, HashMap<String, Entry>>,
+}
+
+// Subscription held in the tree, with the identifier from its SUBSCRIBE properties
+#[derive(Debug, Clone)]
+struct Entry {
+    subscription: Subscription,
+    sub_identifier: Option<u32>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self {
+            tree: RwLock::new(Node::default()),
+            clients: Arc::new(Registry::new()),
+            retain: Arc::new(Retain::new()),
+            sessions: Mutex::new(HashMap::new()),
+            wills: Mutex::new(HashMap::new()),
+            shares: Shares::default(),
+            next_client_id: AtomicU64::new(1),
+        }
+    }
+
+    pub fn clients(&self) -> Arc<Registry> {
+        Arc::clone(&self.clients)
+    }
+
+    pub fn retained(&self) -> Arc<Retain> {
+        Arc::clone(&self.retain)
+    }
+
+    pub fn set_shared_strategy(&self, strategy: SharedStrategy) {
+        self.shares.set_strategy(strategy);
+    }
+
+    // Client id for a client connecting with an empty one, unused by any session
+    pub(crate) fn assign_client_id(&self) -> String {
+        loop {
+            let n = self.next_client_id.fetch_add(1, Ordering::Relaxed);
+            let client_id = format!("rsmqtt-{n}");
+            if !self.clients.contains(&client_id)
+                && !self.sessions.lock().unwrap().contains_key(&client_id)
+            {
+                return client_id;
+            }
+        }
+    }
+
+    // Registers an online client, returning the link it replaces
+    pub(crate) fn connect(&self, client: Client) -> Option<Client> {
+        let mut clients = self.clients.0.write().unwrap();
+        clients.insert(client.client_id.clone(), client)
+    }
+
+    // Takes the link offline, keeping its session until it expires
+    pub(crate) fn disconnect(
+        self: &Arc<Self>,
+        mut session: Session,
+        client: Client,
+        mut rx: UnboundedReceiver<Message>,
+    ) {
+        let mut clients = self.clients.0.write().unwrap();
+        if let Some(c) = clients.get(&client.client_id) {
+            if c.tx.same_channel(&client.tx) {
+                clients.remove(&client.client_id);
+            }
+        }
+        while let Ok(message) = rx.try_recv() {
+            match message {
+                Message::Publish(envelope) if envelope.publish.qos > QoS::AtMostOnce => {
+                    session.enqueue(*envelope);
+                }
+                Message::Takeover(_, reply) => {
+                    // Taken over while closing, the new link gets the session
+                    let _ = reply.send(session);
+                    return;
+                }
+                _ => {}
+            }
+        }
+        // Unacknowledged shared messages move to another group member
+        let released = session.release_shared();
+        let kept = self.redispatch(&clients, &client.client_id, released);
+        session.queue.extend(kept);
+
+        if session.expiry_interval == 0 {
+            drop(clients);
+            self.clear(&session);
+            return;
+        }
+        if let Some(expiry) = session.expiry() {
+            session.expires_at = Some(Instant::now() + expiry);
+            let router = Arc::clone(self);
+            let client_id = session.client_id.clone();
+            task::spawn(async move {
+                sleep(expiry).await;
+                router.expire(&client_id);
+            });
+        }
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.insert(session.client_id.clone(), session);
+    }
+
+    pub(crate) fn resume(&self, client_id: &str) -> Option<Session> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let mut session = sessions.remove(client_id)?;
+        session.expires_at = None;
+        Some(session)
+    }
+
+    fn expire(&self, client_id: &str) {
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions.get(client_id) {
+            Some(session) if session.expired() => {
+                let session = sessions.remove(client_id).unwrap();
+                drop(sessions);
+                self.clear(&session);
+            }
+            _ => {}
+        }
+    }
+
+    // Publishes the will once the delay has passed, unless the client is back
+    pub(crate) fn will(self: &Arc<Self>, client_id: &str, will: Publish, delay: u32) {
+        if delay == 0 {
+            self.publish(client_id, &will);
+            return;
+        }
+        let delay = Duration::from_secs(delay as u64);
+        let mut wills = self.wills.lock().unwrap();
+        wills.insert(client_id.to_owned(), (Instant::now() + delay, will));
+
+        let router = Arc::clone(self);
+        let client_id = client_id.to_owned();
+        task::spawn(async move {
+            sleep(delay).await;
+            let mut wills = router.wills.lock().unwrap();
+            match wills.get(&client_id) {
+                Some((due, _)) if *due <= Instant::now() => {
+                    let (_, will) = wills.remove(&client_id).unwrap();
+                    drop(wills);
+                    router.publish(&client_id, &will);
+                }
+                _ => {}
+            }
+        });
+    }
+
+    pub(crate) fn take_will(&self, client_id: &str) -> Option<Publish> {
+        let mut wills = self.wills.lock().unwrap();
+        wills.remove(client_id).map(|(_, will)| will)
+    }
+
+    pub(crate) fn clear(&self, session: &Session) {
+        for filter in session.subscriptions.keys() {
+            self.unsubscribe(&session.client_id, filter);
+        }
+    }
+
+    pub fn subscribe(
+        &self,
+        client_id: &str,
+        subscription: Subscription,
+        sub_identifier: Option<u32>,
+    ) {
+        let topic = subscription.topic.clone();
+        let entry = Entry {
+            subscription,
+            sub_identifier,
+        };
+        let (group, filter) = split(&topic);
+        let mut tree = self.tree.write().unwrap();
+        let mut node = &mut *tree;
+        for level in filter.split('/') {
+            node = node.children.entry(level.to_owned()).or_default();
+        }
+        match group {
+            Some(group) => {
+                let members = node.shared.entry(group.to_owned()).or_default();
+                members.insert(client_id.to_owned(), entry);
+            }
+            None => {
+                node.subscribers.insert(client_id.to_owned(), entry);
+            }
+        }
+    }
+
+    pub fn unsubscribe(&self, client_id: &str, filter: &str) -> bool {
+        let (group, levels) = split(filter);
+        let mut tree = self.tree.write().unwrap();
+        let levels: Vec<&str> = levels.split('/').collect();
+        let removed = tree.remove(client_id, group, &levels);
+        drop(tree);
+        // Load balancing state goes with the last member of a group
+        if group.is_some() && self.members(filter).is_empty() {
+            self.shares.remove(filter);
+        }
+        removed
+    }
+
+    // Hands messages queued for a group the client left to other members
+    pub(crate) fn release(&self, client_id: &str, envelopes: Vec<Envelope>) -> Vec<Envelope> {
+        if envelopes.is_empty() {
+            return envelopes;
+        }
+        let clients = self.clients.0.read().unwrap();
+        self.redispatch(&clients, client_id, envelopes)
+    }
+
+    // Matching subscriptions per client, the highest QoS wins on overlap
+    pub fn subscribers(&self, topic: &str) -> HashMap<String, Subscription> {
+        let (matched, _) = self.route("", topic);
+        matched.into_iter().map(|(c, (s, _))| (c, s)).collect()
+    }
+
+    // Subscriptions with no local skip messages published by their own client
+    fn route(&self, from: &str, topic: &str) -> (Matched, Groups) {
+        let tree = self.tree.read().unwrap();
+        let levels: Vec<&str> = topic.split('/').collect();
+        let mut matched = HashMap::new();
+        let mut groups = HashMap::new();
+        if topic.starts_with('$') {
+            // Leading wildcards never match topics beginning with $
+            if let Some(child) = tree.children.get(levels[0]) {
+                child.collect(from, &levels[1..], &mut matched, &mut groups);
+            }
+        } else {
+            tree.collect(from, &levels, &mut matched, &mut groups);
+        }
+        (matched, groups)
+    }
+
+    // Current members of a shared subscription
+    fn members(&self, share: &str) -> Vec<(String, Entry)> {
+        let (Some(group), filter) = split(share) else {
+            return Vec::new();
+        };
+        let tree = self.tree.read().unwrap();
+        let mut node = &*tree;
+        for level in filter.split('/') {
+            match node.children.get(level) {
+                Some(child) => node = child,
+                None => return Vec::new(),
+            }
+        }
+        match node.shared.get(group) {
+            Some(members) => members
+                .iter()
+                .map(|(c, s)| (c.clone(), s.clone()))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn publish(&self, from: &str, publish: &Publish) {
+        if publish.retain {
+            self.retain.insert(publish);
+        }
+
+        let envelope = Envelope::new(publish.clone());
+        let (subscribers, groups) = self.route(from, &publish.topic_name);
+        let clients = self.clients.0.read().unwrap();
+        for (client_id, (subscription, identifiers)) in &subscribers {
+            self.send(&clients, client_id, subscription, identifiers, &envelope);
+        }
+        // One member of each shared group gets the message
+        for (share, members) in &groups {
+            let topic = &publish.topic_name;
+            if let Some((client_id, entry)) = self.pick(&clients, share, members, from, topic) {
+                let identifiers: Vec<u32> = entry.sub_identifier.into_iter().collect();
+                self.send(
+                    &clients,
+                    client_id,
+                    &entry.subscription,
+                    &identifiers,
+                    &envelope,
+                );
+            }
+        }
+    }
+
+    // Returns the messages no other member could take
+    fn redispatch(
+        &self,
+        clients: &HashMap<String, Client>,
+        client_id: &str,
+        envelopes: Vec<Envelope>,
+    ) -> Vec<Envelope> {
+        let mut kept = Vec::new();
+        for envelope in envelopes {
+            let Some(share) = &envelope.share else {
+                continue;
+            };
+            let mut members = self.members(share);
+            members.retain(|(c, _)| c != client_id);
+            let topic = &envelope.publish.topic_name;
+            match self.pick(clients, share, &members, client_id, topic) {
+                Some((c, entry)) => {
+                    let identifiers: Vec<u32> = entry.sub_identifier.into_iter().collect();
+                    self.send(clients, c, &entry.subscription, &identifiers, &envelope);
+                }
+                None => kept.push(envelope),
+            }
+        }
+        kept
+    }
+
+    // Online members are preferred over offline sessions
+    fn pick<'a>(
+        &self,
+        clients: &HashMap<String, Client>,
+        share: &str,
+        members: &'a [(String, Entry)],
+        from: &str,
+        topic: &str,
+    ) -> Option<&'a (String, Entry)> {
+        let mut candidates: Vec<&str> = members
+            .iter()
+            .map(|(c, _)| c.as_str())
+            .filter(|c| clients.contains_key(*c))
+            .collect();
+        if candidates.is_empty() {
+            candidates = members.iter().map(|(c, _)| c.as_str()).collect();
+        }
+        if candidates.is_empty() {
+            return None;
+        }
+        candidates.sort_unstable();
+        let chosen = self.shares.choose(share, &candidates, from, topic);
+        members.iter().find(|(c, _)| c == chosen)
+    }
+
+    fn send(
+        &self,
+        clients: &HashMap<String, Client>,
+        client_id: &str,
+        subscription: &Subscription,
+        identifiers: &[u32],
+        envelope: &Envelope,
+    ) {
+        let mut message = envelope.clone();
+        message.publish.dup = false;
+        message.publish.retain = envelope.publish.retain && subscription.retain_as_published;
+        message.publish.packet_id = 0;
+        if subscription.qos < message.publish.qos {
+            message.publish.qos = subscription.qos;
+        }
+        if let Some(props) = message.publish.properties.as_mut() {
+            props.sub_identifier.clear();
+        }
+        if !identifiers.is_empty() {
+            let props = message
+                .publish
+                .properties
+                .get_or_insert_with(PublishProperties::new);
+            props.sub_identifier = identifiers.to_vec();
+        }
+        message.share = topic::shared(&subscription.topic).map(|_| subscription.topic.clone());
+
+        if let Some(client) = clients.get(client_id) {
+            let _ = client.tx.send(Message::Publish(Box::new(message)));
+        } else if message.publish.qos > QoS::AtMostOnce {
+            // Offline session keeps QoS 1/2 messages
+            let mut sessions = self.sessions.lock().unwrap();
+            if let Some(session) = sessions.get_mut(client_id) {
+                session.enqueue(message);
+            }
+        }
+    }
+}
+impl Default for Router {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Node {
+    fn is_empty(&self) -> bool {
+        self.children.is_empty() && self.subscribers.is_empty() && self.shared.is_empty()
+    }
+
+    fn remove(&mut self, client_id: &str, group: Option<&str>, levels: &[&str]) -> bool {
+        let Some((level, rest)) = levels.split_first() else {
+            let Some(group) = group else {
+                return self.subscribers.remove(client_id).is_some();
+            };
+            let Some(members) = self.shared.get_mut(group) else {
+                return false;
+            };
+            let removed = members.remove(client_id).is_some();
+            if members.is_empty() {
+                self.shared.remove(group);
+            }
+            return removed;
+        };
+        let Some(child) = self.children.get_mut(*level) else {
+            return false;
+        };
+        let removed = child.remove(client_id, group, rest);
+        if child.is_empty() {
+            self.children.remove(*level);
+        }
+        removed
+    }
+
+    fn collect(&self, from: &str, levels: &[&str], matched: &mut Matched, groups: &mut Groups) {
+        if let Some(child) = self.children.get("#") {
+            child.gather(from, matched, groups);
+        }
+        let Some((level, rest)) = levels.split_first() else {
+            self.gather(from, matched, groups);
+            return;
+        };
+        if let Some(child) = self.children.get("+") {
+            child.collect(from, rest, matched, groups);
+        }
+        if let Some(child) = self.children.get(*level) {
+            child.collect(from, rest, matched, groups);
+        }
+    }
+
+    fn gather(&self, from: &str, matched: &mut Matched, groups: &mut Groups) {
+        merge(from, &self.subscribers, matched);
+        for members in self.shared.values() {
+            // Every member of a group subscribed with the same $share filter
+            let Some(entry) = members.values().next() else {
+                continue;
+            };
+            let members = members
+                .iter()
+                .map(|(c, e)| (c.clone(), e.clone()))
+                .collect();
+            groups.insert(entry.subscription.topic.clone(), members);
+        }
+    }
+}
+
+// Splits a filter into its shared group, if any, and the topic filter
+fn split(filter: &str) -> (Option<&str>, &str) {
+    match topic::shared(filter) {
+        Some((group, filter)) => (Some(group), filter),
+        None => (None, filter),
+    }
+}
+
+// Identifiers of every overlapping subscription are kept
+fn merge(from: &str, subscribers: &HashMap<String, Entry>, matched: &mut Matched) {
+    for (client_id, entry) in subscribers {
+        let subscription = &entry.subscription;
+        if subscription.no_local && client_id == from {
+            continue;
+        }
+        let (s, identifiers) = matched
+            .entry(client_id.clone())
+            .or_insert_with(|| (subscription.clone(), Vec::new()));
+        if s.qos < subscription.qos {
+            *s = subscription.clone();
+        }
+        identifiers.extend(entry.sub_identifier);
+    }
+}
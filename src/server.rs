@@ -17,13 +17,37 @@ use ws_stream_tungstenite::WsStream;
 pub struct MqttServer {
     listeners: Vec<Listener>,
     hook: Arc<Hook>,
+    router: Arc<Router>,
+    config: Config,
     proxy_protocol: bool,
 }
+
+// Broker limits applied to every link
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub receive_maximum: u16,
+    pub topic_alias_max: u16,
+    pub max_packet_size: u32,
+    pub sub_identifier_available: bool,
+}
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            receive_maximum: 65535,
+            topic_alias_max: 32,
+            max_packet_size: 1024 * 1024,
+            sub_identifier_available: true,
+        }
+    }
+}
+
 impl MqttServer {
     pub fn new() -> Self {
         Self {
             listeners: Vec::new(),
             hook: Arc::new(Hook::new()),
+            router: Arc::new(Router::new()),
+            config: Config::default(),
             proxy_protocol: false,
         }
     }
@@ -54,13 +78,37 @@ impl MqttServer {
         self
     }
 
+    pub fn clients(&self) -> Arc<Registry> {
+        self.router.clients()
+    }
+
     pub fn proxy_protocol(&mut self, proxy: bool) -> &mut Self {
         self.proxy_protocol = proxy;
         self
     }
+    pub fn receive_maximum(&mut self, receive_maximum: u16) -> &mut Self {
+        self.config.receive_maximum = receive_maximum.max(1);
+        self
+    }
+    pub fn sub_identifier_available(&mut self, available: bool) -> &mut Self {
+        self.config.sub_identifier_available = available;
+        self
+    }
+    pub fn max_packet_size(&mut self, max_packet_size: u32) -> &mut Self {
+        self.config.max_packet_size = max_packet_size;
+        self
+    }
+    pub fn topic_alias_max(&mut self, topic_alias_max: u16) -> &mut Self {
+        self.config.topic_alias_max = topic_alias_max;
+        self
+    }
+    pub fn shared_strategy(&mut self, strategy: SharedStrategy) -> &mut Self {
+        self.router.set_shared_strategy(strategy);
+        self
+    }
     pub fn connect(
         &mut self,
-        f: impl Fn(Connect) -> Result<Packet, Error> + Send + Sync + 'static,
+        f: impl Fn(&Connect) -> Result<Packet, Error> + Send + Sync + 'static,
     ) -> &mut Self {
         self.hook.register(move |packet| match packet {
             Packet::Connect(connect) => f(connect),
@@ -70,7 +118,7 @@ impl MqttServer {
     }
     pub fn publish(
         &mut self,
-        f: impl Fn(Publish) -> Result<Packet, Error> + Send + Sync + 'static,
+        f: impl Fn(&Publish) -> Result<Packet, Error> + Send + Sync + 'static,
     ) -> &mut Self {
         self.hook.register(move |packet| match packet {
             Packet::Publish(publish) => f(publish),
@@ -82,10 +130,13 @@ impl MqttServer {
         if self.listeners.is_empty() {
             self.tcp("0.0.0.0:1883");
         }
+        let config = Arc::new(self.config.clone());
         for listen in self.listeners.clone() {
             let hook = Arc::clone(&self.hook);
+            let router = Arc::clone(&self.router);
+            let config = Arc::clone(&config);
             task::spawn(async move {
-                if let Err(e) = listen.start(hook).await {
+                if let Err(e) = listen.start(hook, router, config).await {
                     println!("{}", e);
                 }
             });
@@ -93,6 +144,11 @@ impl MqttServer {
         Ok(())
     }
 }
+impl Default for MqttServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 #[derive(Debug, Clone)]
 struct Listener {
     protocol: String,
@@ -101,7 +157,12 @@ struct Listener {
     key: String,
 }
 impl Listener {
-    async fn start(&self, hook: Arc<Hook>) -> Result<(), Error> {
+    async fn start(
+        &self,
+        hook: Arc<Hook>,
+        router: Arc<Router>,
+        config: Arc<Config>,
+    ) -> Result<(), Error> {
         let protocol = self.protocol.as_str();
         println!("{}", protocol);
         let acceptor = match protocol {
@@ -118,10 +179,12 @@ impl Listener {
                 Err(_) => continue,
             };
             let hook = Arc::clone(&hook);
+            let router = Arc::clone(&router);
+            let config = Arc::clone(&config);
             match protocol {
                 "tcp" => {
                     let stream = Box::new(stream);
-                    task::spawn(Link::new(stream, hook).serve());
+                    task::spawn(Link::new(stream, hook, router, config).serve());
                 }
                 "tls" => {
                     let acceptor = acceptor.clone().unwrap();
@@ -129,7 +192,7 @@ impl Listener {
                         Ok(stream) => Box::new(stream),
                         Err(_) => continue,
                     };
-                    task::spawn(Link::new(stream, hook).serve());
+                    task::spawn(Link::new(stream, hook, router, config).serve());
                 }
                 "ws" => {
                     let stream = match accept_hdr_async(stream, WSCallback).await {
@@ -137,7 +200,7 @@ impl Listener {
                         Err(_) => continue,
                     };
                     let stream = Box::new(WsStream::new(stream));
-                    task::spawn(Link::new(stream, hook).serve());
+                    task::spawn(Link::new(stream, hook, router, config).serve());
                 }
                 "wss" => {
                     let acceptor = acceptor.clone().unwrap();
@@ -150,7 +213,7 @@ impl Listener {
                         Err(_) => continue,
                     };
                     let stream = Box::new(WsStream::new(stream));
-                    task::spawn(Link::new(stream, hook).serve());
+                    task::spawn(Link::new(stream, hook, router, config).serve());
                 }
                 _ => (),
             }
@@ -0,0 +1,177 @@
+use crate::*;
+use bytes::Bytes;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::Duration;
+use tokio::time::Instant;
+
+// Outbound QoS 1/2 message waiting for the client
+#[derive(Debug, Clone)]
+pub(crate) enum Inflight {
+    // Waiting for PUBACK or PUBREC
+    Publish(Box<Envelope>),
+    // Waiting for PUBCOMP
+    PubRel,
+}
+
+// Publish held by the broker until its message expiry interval runs out
+#[derive(Debug, Clone)]
+pub(crate) struct Envelope {
+    pub(crate) publish: Publish,
+    pub(crate) expires_at: Option<Instant>,
+    // Shared subscription the message was delivered through
+    pub(crate) share: Option<String>,
+}
+
+impl Envelope {
+    pub(crate) fn new(publish: Publish) -> Self {
+        let expires_at = publish
+            .properties
+            .as_ref()
+            .and_then(|p| p.message_expiry_interval)
+            .map(|interval| Instant::now() + Duration::from_secs(interval as u64));
+        Self {
+            publish,
+            expires_at,
+            share: None,
+        }
+    }
+
+    pub(crate) fn expired(&self) -> bool {
+        match self.expires_at {
+            Some(expires_at) => expires_at <= Instant::now(),
+            None => false,
+        }
+    }
+
+    // The expiry interval is rewritten to the remaining lifetime
+    pub(crate) fn open(self) -> Option<Publish> {
+        let mut publish = self.publish;
+        if let Some(expires_at) = self.expires_at {
+            let remaining = expires_at.checked_duration_since(Instant::now())?;
+            if remaining.is_zero() {
+                return None;
+            }
+            let props = publish
+                .properties
+                .get_or_insert_with(PublishProperties::new);
+            props.message_expiry_interval = Some(remaining.as_secs_f64().ceil() as u32);
+        }
+        Some(publish)
+    }
+}
+
+// Session state kept across network connections
+#[derive(Debug, Default)]
+pub struct Session {
+    pub client_id: String,
+    pub expiry_interval: u32,
+    pub subscriptions: HashMap<String, Subscription>,
+    pub(crate) queue: VecDeque<Envelope>,
+    pub(crate) inflight: VecDeque<(u16, Inflight)>,
+    // Inbound QoS 2 packet ids waiting for PUBREL
+    pub(crate) incoming: HashSet<u16>,
+    pub(crate) packet_id: u16,
+    pub(crate) expires_at: Option<Instant>,
+}
+
+impl Session {
+    pub fn new(client_id: &str) -> Self {
+        Self {
+            client_id: client_id.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    // Queued messages can wait a long time, so their payload stops sharing the
+    // read buffer it was decoded from
+    pub(crate) fn enqueue(&mut self, mut envelope: Envelope) {
+        envelope.publish.payload = Bytes::copy_from_slice(&envelope.publish.payload);
+        self.queue.push_back(envelope);
+    }
+
+    // Skips packet ids still in flight
+    pub(crate) fn next_packet_id(&mut self) -> u16 {
+        loop {
+            self.packet_id = self.packet_id.wrapping_add(1);
+            if self.packet_id != 0 && self.position(self.packet_id).is_none() {
+                return self.packet_id;
+            }
+        }
+    }
+
+    fn position(&self, packet_id: u16) -> Option<usize> {
+        self.inflight.iter().position(|(id, _)| *id == packet_id)
+    }
+
+    pub(crate) fn puback(&mut self, packet_id: u16) -> bool {
+        match self.position(packet_id) {
+            Some(i) if matches!(self.inflight[i].1, Inflight::Publish(_)) => {
+                self.inflight.remove(i);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // QoS 2 message received, PUBREL goes out next
+    pub(crate) fn pubrec(&mut self, packet_id: u16) -> bool {
+        match self.position(packet_id) {
+            Some(i) => {
+                self.inflight[i].1 = Inflight::PubRel;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub(crate) fn pubcomp(&mut self, packet_id: u16) -> bool {
+        match self.position(packet_id) {
+            Some(i) if matches!(self.inflight[i].1, Inflight::PubRel) => {
+                self.inflight.remove(i);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Shared messages not yet acknowledged go back to the group
+    pub(crate) fn release_shared(&mut self) -> Vec<Envelope> {
+        let (shared, queue): (VecDeque<_>, _) =
+            self.queue.drain(..).partition(|e| e.share.is_some());
+        self.queue = queue;
+        let mut shared = Vec::from(shared);
+        self.inflight.retain(|(_, inflight)| match inflight {
+            Inflight::Publish(envelope) if envelope.share.is_some() => {
+                shared.push(Envelope::clone(envelope));
+                false
+            }
+            _ => true,
+        });
+        shared
+    }
+
+    // Queued messages of one shared subscription, taken when leaving its group
+    pub(crate) fn release_queued(&mut self, share: &str) -> Vec<Envelope> {
+        let (shared, queue): (VecDeque<_>, _) = self
+            .queue
+            .drain(..)
+            .partition(|e| e.share.as_deref() == Some(share));
+        self.queue = queue;
+        Vec::from(shared)
+    }
+
+    // 0xFFFFFFFF means the session does not expire
+    pub(crate) fn expiry(&self) -> Option<Duration> {
+        match self.expiry_interval {
+            u32::MAX => None,
+            interval => Some(Duration::from_secs(interval as u64)),
+        }
+    }
+
+    pub(crate) fn expired(&self) -> bool {
+        match self.expires_at {
+            Some(expires_at) => expires_at <= Instant::now(),
+            None => false,
+        }
+    }
+}
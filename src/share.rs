@@ -0,0 +1,74 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Mutex, RwLock};
+
+// How a shared subscription group picks the member receiving a message
+#[derive(Debug, Default, PartialEq, Copy, Clone)]
+pub enum SharedStrategy {
+    #[default]
+    RoundRobin,
+    Random,
+    Sticky,
+    HashClientId,
+    HashTopic,
+}
+
+// Load-balancing state per shared subscription
+#[derive(Default)]
+pub(crate) struct Shares {
+    strategy: RwLock<SharedStrategy>,
+    cursors: Mutex<HashMap<String, usize>>,
+    sticky: Mutex<HashMap<String, String>>,
+}
+
+impl Shares {
+    pub(crate) fn set_strategy(&self, strategy: SharedStrategy) {
+        *self.strategy.write().unwrap() = strategy;
+    }
+
+    pub(crate) fn remove(&self, share: &str) {
+        self.cursors.lock().unwrap().remove(share);
+        self.sticky.lock().unwrap().remove(share);
+    }
+
+    // Members are sorted by client id so hashing is stable
+    pub(crate) fn choose<'a>(
+        &self,
+        share: &str,
+        members: &[&'a str],
+        client_id: &str,
+        topic: &str,
+    ) -> &'a str {
+        let strategy = *self.strategy.read().unwrap();
+        let i = match strategy {
+            SharedStrategy::RoundRobin => {
+                let mut cursors = self.cursors.lock().unwrap();
+                let cursor = cursors.entry(share.to_owned()).or_insert(0);
+                *cursor = cursor.wrapping_add(1);
+                *cursor
+            }
+            SharedStrategy::Random => fastrand::usize(..),
+            SharedStrategy::Sticky => {
+                let mut sticky = self.sticky.lock().unwrap();
+                if let Some(member) = sticky.get(share) {
+                    if let Some(&member) = members.iter().find(|&m| m == member) {
+                        return member;
+                    }
+                }
+                let member = members[fastrand::usize(..members.len())];
+                sticky.insert(share.to_owned(), member.to_owned());
+                return member;
+            }
+            SharedStrategy::HashClientId => hash(client_id),
+            SharedStrategy::HashTopic => hash(topic),
+        };
+        members[i % members.len()]
+    }
+}
+
+fn hash(key: &str) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish() as usize
+}
@@ -0,0 +1,133 @@
+// Topic names and topic filters
+
+const SHARE: &str = "$share/";
+
+pub fn valid_name(topic: &str) -> bool {
+    !topic.is_empty() && !topic.contains(['+', '#', '\0'])
+}
+
+pub fn valid_filter(filter: &str) -> bool {
+    if filter.is_empty() || filter.contains('\0') {
+        return false;
+    }
+
+    let filter = match shared(filter) {
+        Some((group, filter)) if !group.is_empty() && !group.contains(['+', '#']) => {
+            if filter.is_empty() {
+                return false;
+            }
+            filter
+        }
+        Some(_) => return false,
+        None if filter.starts_with(SHARE) => return false,
+        None => filter,
+    };
+
+    let mut levels = filter.split('/').peekable();
+    while let Some(level) = levels.next() {
+        match level {
+            "#" if levels.peek().is_some() => return false,
+            "#" | "+" => {}
+            _ if level.contains(['+', '#']) => return false,
+            _ => {}
+        }
+    }
+    true
+}
+
+// Splits $share/{group}/{filter} into group and filter
+pub fn shared(filter: &str) -> Option<(&str, &str)> {
+    filter.strip_prefix(SHARE)?.split_once('/')
+}
+
+pub fn matches(filter: &str, topic: &str) -> bool {
+    // Leading wildcards never match topics beginning with $
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+
+    let mut filter = filter.split('/');
+    let mut topic = topic.split('/');
+    loop {
+        match (filter.next(), topic.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(f), Some(t)) if f == t => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names() {
+        assert!(valid_name("a/b/c"));
+        assert!(valid_name("/"));
+        assert!(valid_name("$SYS/uptime"));
+        assert!(!valid_name(""));
+        assert!(!valid_name("a/+/c"));
+        assert!(!valid_name("a/#"));
+        assert!(!valid_name("a\0b"));
+    }
+
+    #[test]
+    fn filters() {
+        assert!(valid_filter("#"));
+        assert!(valid_filter("+"));
+        assert!(valid_filter("a/#"));
+        assert!(valid_filter("+/b/+"));
+        assert!(valid_filter("a//b"));
+        assert!(!valid_filter(""));
+        assert!(!valid_filter("a/#/b"));
+        assert!(!valid_filter("#/b"));
+        assert!(!valid_filter("a#"));
+        assert!(!valid_filter("a/b+"));
+        assert!(!valid_filter("a/+b/c"));
+        assert!(!valid_filter("a\0b"));
+    }
+
+    #[test]
+    fn shared_filters() {
+        assert_eq!(shared("$share/g/a/b"), Some(("g", "a/b")));
+        assert_eq!(shared("$share/g/#"), Some(("g", "#")));
+        assert_eq!(shared("$share/g"), None);
+        assert_eq!(shared("a/b"), None);
+
+        assert!(valid_filter("$share/g/a/+"));
+        assert!(valid_filter("$share/g/#"));
+        assert!(!valid_filter("$share/g"));
+        assert!(!valid_filter("$share/g/"));
+        assert!(!valid_filter("$share//a"));
+        assert!(!valid_filter("$share/+/a"));
+        assert!(!valid_filter("$share/g#/a"));
+        assert!(!valid_filter("$share/g/a/#/b"));
+    }
+
+    #[test]
+    fn matching() {
+        assert!(matches("a/b", "a/b"));
+        assert!(!matches("a/b", "a/b/c"));
+        assert!(!matches("a/b/c", "a/b"));
+        assert!(matches("a/+/c", "a/b/c"));
+        assert!(matches("a/+", "a/"));
+        assert!(!matches("a/+", "a"));
+        assert!(matches("+/+", "/b"));
+        assert!(matches("a/#", "a"));
+        assert!(matches("a/#", "a/b/c"));
+        assert!(matches("#", "a/b"));
+        assert!(!matches("a/#", "b/c"));
+    }
+
+    #[test]
+    fn dollar_topics() {
+        assert!(!matches("#", "$SYS/uptime"));
+        assert!(!matches("+/uptime", "$SYS/uptime"));
+        assert!(matches("$SYS/#", "$SYS/uptime"));
+        assert!(matches("$SYS/+", "$SYS/uptime"));
+        assert!(matches("a/+", "a/$b"));
+    }
+}
@@ -0,0 +1,919 @@
+// Broker behaviour seen from connected clients
+
+mod common;
+
+use common::*;
+use rsmqtt::*;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::{sleep, timeout};
+
+#[tokio::test]
+async fn wildcard_fan_out() {
+    let addr = "127.0.0.1:18841";
+    server(addr).await;
+
+    let mut exact = client(addr, "exact").await;
+    let mut single = client(addr, "single").await;
+    let mut multi = client(addr, "multi").await;
+    let pubc = client(addr, "pub").await;
+    exact.subscribe("a/b/c", QoS::AtMostOnce).await.unwrap();
+    single.subscribe("a/+/c", QoS::AtMostOnce).await.unwrap();
+    multi.subscribe("a/#", QoS::AtMostOnce).await.unwrap();
+
+    pubc.publish("a/b/c", "1", QoS::AtMostOnce, false)
+        .await
+        .unwrap();
+    for sub in [&mut exact, &mut single, &mut multi] {
+        let publish = recv(sub).await;
+        assert_eq!(publish.topic_name, "a/b/c");
+        assert_eq!(publish.payload, "1");
+    }
+
+    pubc.publish("a/x", "2", QoS::AtMostOnce, false)
+        .await
+        .unwrap();
+    assert_eq!(recv(&mut multi).await.payload, "2");
+    silent(&mut exact).await;
+    silent(&mut single).await;
+
+    pubc.publish("b/c", "3", QoS::AtMostOnce, false)
+        .await
+        .unwrap();
+    silent(&mut multi).await;
+}
+
+#[tokio::test]
+async fn overlapping_subscriptions() {
+    let addr = "127.0.0.1:18842";
+    server(addr).await;
+
+    // One copy per client, at the highest matching QoS
+    let mut sub = client(addr, "sub").await;
+    let pubc = client(addr, "pub").await;
+    sub.subscribe("a/+", QoS::AtMostOnce).await.unwrap();
+    sub.subscribe("a/#", QoS::AtLeastOnce).await.unwrap();
+
+    pubc.publish("a/b", "x", QoS::AtLeastOnce, false)
+        .await
+        .unwrap();
+    let publish = recv(&mut sub).await;
+    assert_eq!(publish.qos, QoS::AtLeastOnce);
+    silent(&mut sub).await;
+
+    // Delivered at no more than the subscription QoS
+    sub.unsubscribe("a/#").await.unwrap();
+    pubc.publish("a/b", "y", QoS::ExactlyOnce, false)
+        .await
+        .unwrap();
+    assert_eq!(recv(&mut sub).await.qos, QoS::AtMostOnce);
+}
+
+async fn persistent(addr: &str, client_id: &str) -> (MqttClient, ConnAck) {
+    let mut props = ConnectProperties::new();
+    props.session_expiry_interval = Some(60);
+    let mut client = MqttClient::new();
+    client
+        .client_id(client_id)
+        .clean_start(false)
+        .properties(props);
+    let connack = client.connect(addr).await.unwrap();
+    (client, connack)
+}
+
+#[tokio::test]
+async fn session_resume() {
+    let addr = "127.0.0.1:18843";
+    server(addr).await;
+
+    let (mut sub, connack) = persistent(addr, "durable").await;
+    assert!(!connack.session_present);
+    sub.subscribe("q", QoS::AtLeastOnce).await.unwrap();
+    sub.disconnect().await.unwrap();
+
+    // Queued while offline, QoS 0 is not kept
+    let pubc = client(addr, "pub").await;
+    pubc.publish("q", "0", QoS::AtMostOnce, false)
+        .await
+        .unwrap();
+    pubc.publish("q", "1", QoS::AtLeastOnce, false)
+        .await
+        .unwrap();
+    pubc.publish("q", "2", QoS::ExactlyOnce, false)
+        .await
+        .unwrap();
+
+    let (mut sub, connack) = persistent(addr, "durable").await;
+    assert!(connack.session_present);
+    let publish = recv(&mut sub).await;
+    assert_eq!(publish.payload, "1");
+    assert_eq!(publish.qos, QoS::AtLeastOnce);
+    let publish = recv(&mut sub).await;
+    assert_eq!(publish.payload, "2");
+    assert_eq!(publish.qos, QoS::AtLeastOnce);
+    silent(&mut sub).await;
+
+    // The subscription came back with the session
+    pubc.publish("q", "3", QoS::AtLeastOnce, false)
+        .await
+        .unwrap();
+    assert_eq!(recv(&mut sub).await.payload, "3");
+}
+
+#[tokio::test]
+async fn clean_start_discards_session() {
+    let addr = "127.0.0.1:18844";
+    server(addr).await;
+
+    let (mut sub, _) = persistent(addr, "fresh").await;
+    sub.subscribe("q", QoS::AtLeastOnce).await.unwrap();
+    sub.disconnect().await.unwrap();
+
+    let pubc = client(addr, "pub").await;
+    pubc.publish("q", "lost", QoS::AtLeastOnce, false)
+        .await
+        .unwrap();
+
+    let mut sub = MqttClient::new();
+    sub.client_id("fresh");
+    let connack = sub.connect(addr).await.unwrap();
+    assert!(!connack.session_present);
+    silent(&mut sub).await;
+}
+
+async fn subscribe_with(client: &MqttClient, filter: &str, retain_handling: RetainHandling) {
+    let mut subscribe = Subscribe::new();
+    subscribe.payload.push(Subscription {
+        topic: filter.to_owned(),
+        qos: QoS::AtLeastOnce,
+        retain_handling,
+        ..Default::default()
+    });
+    client.subscribe_with(subscribe).await.unwrap();
+}
+
+#[tokio::test]
+async fn retained_replay() {
+    let addr = "127.0.0.1:18845";
+    server(addr).await;
+
+    let pubc = client(addr, "pub").await;
+    pubc.publish("r/a", "a", QoS::AtLeastOnce, true)
+        .await
+        .unwrap();
+    pubc.publish("r/b", "b", QoS::AtLeastOnce, true)
+        .await
+        .unwrap();
+    // Replaces the earlier retained message
+    pubc.publish("r/b", "b2", QoS::AtLeastOnce, true)
+        .await
+        .unwrap();
+
+    let mut sub = client(addr, "sub").await;
+    sub.subscribe("r/#", QoS::AtLeastOnce).await.unwrap();
+    let mut replayed = [recv(&mut sub).await, recv(&mut sub).await];
+    replayed.sort_by(|a, b| a.topic_name.cmp(&b.topic_name));
+    assert!(replayed.iter().all(|publish| publish.retain));
+    assert_eq!(replayed[0].payload, "a");
+    assert_eq!(replayed[1].payload, "b2");
+    silent(&mut sub).await;
+
+    // Live messages lose the retain flag
+    pubc.publish("r/c", "c", QoS::AtLeastOnce, true)
+        .await
+        .unwrap();
+    assert!(!recv(&mut sub).await.retain);
+
+    // An empty payload removes the retained message
+    pubc.publish("r/a", "", QoS::AtLeastOnce, true)
+        .await
+        .unwrap();
+    recv(&mut sub).await;
+    let mut late = client(addr, "late").await;
+    late.subscribe("r/a", QoS::AtLeastOnce).await.unwrap();
+    silent(&mut late).await;
+}
+
+#[tokio::test]
+async fn retain_handling() {
+    let addr = "127.0.0.1:18846";
+    server(addr).await;
+
+    let pubc = client(addr, "pub").await;
+    pubc.publish("h", "kept", QoS::AtLeastOnce, true)
+        .await
+        .unwrap();
+
+    let mut sub = client(addr, "sub").await;
+    subscribe_with(&sub, "h", RetainHandling::Never).await;
+    silent(&mut sub).await;
+    sub.unsubscribe("h").await.unwrap();
+
+    // Only for a subscription that did not exist yet
+    subscribe_with(&sub, "h", RetainHandling::NewSub).await;
+    assert_eq!(recv(&mut sub).await.payload, "kept");
+    subscribe_with(&sub, "h", RetainHandling::NewSub).await;
+    silent(&mut sub).await;
+
+    // On every subscribe
+    subscribe_with(&sub, "h", RetainHandling::Sub).await;
+    assert_eq!(recv(&mut sub).await.payload, "kept");
+}
+
+async fn will_client(addr: &str, client_id: &str, delay: Option<u32>) -> MqttClient {
+    let mut client = MqttClient::new();
+    client
+        .client_id(client_id)
+        .will("will", "gone", QoS::AtLeastOnce, false);
+    if let Some(delay) = delay {
+        let mut will_props = WillProperties::new();
+        will_props.will_delay_interval = Some(delay);
+        let mut props = ConnectProperties::new();
+        props.session_expiry_interval = Some(60);
+        client
+            .clean_start(false)
+            .will_properties(will_props)
+            .properties(props);
+    }
+    client.connect(addr).await.unwrap();
+    client
+}
+
+#[tokio::test]
+async fn will_publish() {
+    let addr = "127.0.0.1:18847";
+    let server = server(addr).await;
+    let mut sub = client(addr, "sub").await;
+    sub.subscribe("will", QoS::AtLeastOnce).await.unwrap();
+
+    // A normal DISCONNECT discards the will
+    let mut polite = will_client(addr, "polite", None).await;
+    polite.disconnect().await.unwrap();
+    silent(&mut sub).await;
+
+    // Closed without DISCONNECT
+    let _abrupt = will_client(addr, "abrupt", None).await;
+    assert!(server.clients().kick("abrupt", ReasonCode::AdminAction));
+    let publish = recv(&mut sub).await;
+    assert_eq!(publish.topic_name, "will");
+    assert_eq!(publish.payload, "gone");
+}
+
+#[tokio::test]
+async fn will_topic_invalid() {
+    let addr = "127.0.0.1:18857";
+    server(addr).await;
+    let mut sub = client(addr, "sub").await;
+    sub.subscribe("#", QoS::AtLeastOnce).await.unwrap();
+
+    for topic in ["will/+", "will/#", "will\0", ""] {
+        let mut client = MqttClient::new();
+        client
+            .client_id("wildcard")
+            .will(topic, "gone", QoS::AtLeastOnce, true);
+        let e = client.connect(addr).await.unwrap_err();
+        assert!(matches!(e, Error::Rejected(ReasonCode::TopicNameInvalid)));
+    }
+
+    // Closed without a CONNACK in 3.1.1
+    let mut client = MqttClient::new();
+    client.version(Version::V311).client_id("wildcard").will(
+        "will/#",
+        "gone",
+        QoS::AtLeastOnce,
+        true,
+    );
+    let e = client.connect(addr).await.unwrap_err();
+    assert!(!matches!(e, Error::Rejected(_)));
+    silent(&mut sub).await;
+}
+
+#[tokio::test]
+async fn will_on_invalid_disconnect() {
+    let addr = "127.0.0.1:18848";
+    server(addr).await;
+    let mut sub = client(addr, "sub").await;
+    sub.subscribe("will", QoS::AtLeastOnce).await.unwrap();
+
+    let mut raw = Raw::connect(addr, Version::V5).await;
+    let mut connect = Connect::new();
+    connect.client_id = "invalid".to_owned();
+    connect.clean_start = true;
+    connect.will_flag = true;
+    connect.will_topic = "will".to_owned();
+    connect.will_payload = "gone".into();
+    raw.send(Packet::Connect(connect)).await;
+    assert!(matches!(raw.recv().await, Some(Packet::ConnAck(_))));
+
+    // Session expiry raised from 0 is a protocol error, the will still goes out
+    let mut props = DisconnectProperties::new();
+    props.session_expiry_interval = Some(60);
+    let mut disconnect = Disconnect::new();
+    disconnect.properties = Some(props);
+    raw.send(Packet::Disconnect(disconnect)).await;
+    match raw.recv().await {
+        Some(Packet::Disconnect(disconnect)) => {
+            assert_eq!(disconnect.reason_code, ReasonCode::ProtocolError)
+        }
+        p => panic!("{:?}", p),
+    }
+    assert_eq!(recv(&mut sub).await.payload, "gone");
+}
+
+#[tokio::test]
+async fn will_delay() {
+    let addr = "127.0.0.1:18849";
+    let server = server(addr).await;
+    let mut sub = client(addr, "sub").await;
+    sub.subscribe("will", QoS::AtLeastOnce).await.unwrap();
+
+    // Sent once the delay has passed
+    let _late = will_client(addr, "late", Some(1)).await;
+    assert!(server.clients().kick("late", ReasonCode::AdminAction));
+    silent(&mut sub).await;
+    assert_eq!(recv(&mut sub).await.payload, "gone");
+
+    // Cancelled by reconnecting within the delay
+    let _back = will_client(addr, "back", Some(1)).await;
+    assert!(server.clients().kick("back", ReasonCode::AdminAction));
+    let _back = will_client(addr, "back", Some(1)).await;
+    sleep(Duration::from_millis(1500)).await;
+    silent(&mut sub).await;
+}
+
+#[tokio::test]
+async fn assigned_client_id() {
+    let addr = "127.0.0.1:18850";
+    let server = server(addr).await;
+
+    // Each client without an id gets its own
+    let mut first = MqttClient::new();
+    let ack1 = first.connect(addr).await.unwrap();
+    let mut second = MqttClient::new();
+    let ack2 = second.connect(addr).await.unwrap();
+    let id1 = ack1.properties.unwrap().assigned_client_identifier.unwrap();
+    let id2 = ack2.properties.unwrap().assigned_client_identifier.unwrap();
+    assert_ne!(id1, id2);
+    assert_eq!(server.clients().len(), 2);
+
+    first.subscribe("anon", QoS::AtMostOnce).await.unwrap();
+    second
+        .publish("anon", "hi", QoS::AtMostOnce, false)
+        .await
+        .unwrap();
+    assert_eq!(recv(&mut first).await.payload, "hi");
+
+    // v3.1.1 clean session clients have no way to learn it
+    let mut v3 = MqttClient::new();
+    v3.version(Version::V311);
+    let connack = v3.connect(addr).await.unwrap();
+    assert!(connack.properties.is_none());
+    assert_eq!(server.clients().len(), 3);
+}
+
+async fn refused(addr: &str, version: Version, connect: &[u8]) -> ReasonCode {
+    let mut raw = Raw::connect(addr, version).await;
+    raw.send_bytes(connect).await;
+    let Some(Packet::ConnAck(connack)) = raw.recv().await else {
+        panic!("expected CONNACK");
+    };
+    assert!(raw.recv().await.is_none());
+    connack.reason_code
+}
+
+// Closed without a CONNACK
+async fn dropped(addr: &str, version: Version, connect: &[u8]) {
+    let mut raw = Raw::connect(addr, version).await;
+    raw.send_bytes(connect).await;
+    assert!(raw.recv().await.is_none());
+}
+
+#[tokio::test]
+async fn refused_connect() {
+    let addr = "127.0.0.1:18851";
+    let mut server = server(addr).await;
+    server.connect(|connect| match connect.username.as_str() {
+        "mallory" => Err(Error::Rejected(ReasonCode::BadUserNameOrPassword)),
+        _ => Ok(Packet::None),
+    });
+
+    // Protocol level 6
+    let connect = [0x10, 12, 0, 4, b'M', b'Q', b'T', b'T', 6, 2, 0, 60, 0, 0];
+    let reason_code = refused(addr, Version::V311, &connect).await;
+    assert_eq!(reason_code, ReasonCode::UnsupportedProtocolVersion);
+
+    // Reserved connect flag in v5
+    let connect = [0x10, 13, 0, 4, b'M', b'Q', b'T', b'T', 5, 3, 0, 60, 0, 0, 0];
+    let reason_code = refused(addr, Version::V5, &connect).await;
+    assert_eq!(reason_code, ReasonCode::MalformedPacket);
+
+    // 3.1.1 has no return code for a malformed CONNECT
+    let connect = [0x10, 12, 0, 4, b'M', b'Q', b'T', b'T', 4, 3, 0, 60, 0, 0];
+    dropped(addr, Version::V311, &connect).await;
+
+    // Will QoS without a will
+    let connect = [0x10, 12, 0, 4, b'M', b'Q', b'T', b'T', 4, 0x0A, 0, 60, 0, 0];
+    dropped(addr, Version::V311, &connect).await;
+
+    // 3.1 client ids are 1 to 23 characters
+    let connect = [
+        0x10, 14, 0, 6, b'M', b'Q', b'I', b's', b'd', b'p', 3, 2, 0, 60, 0, 0,
+    ];
+    let reason_code = refused(addr, Version::V31, &connect).await;
+    assert_eq!(reason_code, ReasonCode::ClientIdentifierNotValid);
+
+    let mut client = MqttClient::new();
+    client.client_id("mallory").credentials("mallory", "secret");
+    let e = client.connect(addr).await.unwrap_err();
+    assert!(matches!(
+        e,
+        Error::Rejected(ReasonCode::BadUserNameOrPassword)
+    ));
+}
+
+#[tokio::test]
+async fn ack_too_large() {
+    let addr = "127.0.0.1:18852";
+    server(addr).await;
+
+    let mut props = ConnectProperties::new();
+    props.max_packet_size = Some(20);
+    let mut client = MqttClient::new();
+    client.client_id("tiny").properties(props);
+    let mut events = client.events().unwrap();
+    client.connect(addr).await.unwrap();
+
+    // A SUBACK the client cannot take closes the connection instead of going missing
+    let mut subscribe = Subscribe::new();
+    for i in 0..20 {
+        subscribe.payload.push(Subscription {
+            topic: format!("t/{i}"),
+            ..Default::default()
+        });
+    }
+    let r = timeout(Duration::from_secs(5), client.subscribe_with(subscribe)).await;
+    assert!(r.unwrap().is_err());
+    assert!(matches!(events.recv().await, Some(Event::Connected(_))));
+    assert!(matches!(events.recv().await, Some(Event::Disconnected(_))));
+}
+
+async fn member(addr: &str, client_id: &str, share: &str) -> MqttClient {
+    let client = client(addr, client_id).await;
+    client.subscribe(share, QoS::AtLeastOnce).await.unwrap();
+    client
+}
+
+#[tokio::test]
+async fn shared_distribution() {
+    let addr = "127.0.0.1:18853";
+    server(addr).await;
+
+    let mut a = member(addr, "a", "$share/g/s/+").await;
+    let mut b = member(addr, "b", "$share/g/s/+").await;
+    // Another group gets its own copy
+    let mut other = member(addr, "other", "$share/h/s/+").await;
+    let pubc = client(addr, "pub").await;
+
+    for i in 0..10 {
+        pubc.publish("s/x", format!("{i}"), QoS::AtLeastOnce, false)
+            .await
+            .unwrap();
+    }
+    let mut got = Vec::new();
+    for _ in 0..5 {
+        got.push(recv(&mut a).await.payload);
+        got.push(recv(&mut b).await.payload);
+    }
+    silent(&mut a).await;
+    silent(&mut b).await;
+    got.sort_by_key(|payload| String::from_utf8_lossy(payload).parse::<u32>().unwrap());
+    let expected: Vec<_> = (0..10).map(|i| format!("{i}")).collect();
+    assert_eq!(got, expected);
+    for i in 0..10 {
+        assert_eq!(recv(&mut other).await.payload, format!("{i}"));
+    }
+}
+
+#[tokio::test]
+async fn shared_unsubscribe() {
+    let addr = "127.0.0.1:18854";
+    server(addr).await;
+
+    // Member with a full receive window, so later messages stay queued
+    let mut props = ConnectProperties::new();
+    props.receive_maximum = Some(1);
+    let mut slow = Raw::connect(addr, Version::V5).await;
+    let mut connect = Connect::new();
+    connect.client_id = "slow".to_owned();
+    connect.clean_start = true;
+    connect.properties = Some(props);
+    slow.send(Packet::Connect(connect)).await;
+    assert!(matches!(slow.recv().await, Some(Packet::ConnAck(_))));
+    let mut subscribe = Subscribe::new();
+    subscribe.packet_id = 1;
+    subscribe.payload.push(Subscription {
+        topic: "$share/g/q".to_owned(),
+        qos: QoS::AtLeastOnce,
+        ..Default::default()
+    });
+    slow.send(Packet::Subscribe(subscribe)).await;
+    assert!(matches!(slow.recv().await, Some(Packet::SubAck(_))));
+
+    let mut fast = member(addr, "fast", "$share/g/q").await;
+    let pubc = client(addr, "pub").await;
+    for i in 0..6 {
+        pubc.publish("q", format!("{i}"), QoS::AtLeastOnce, false)
+            .await
+            .unwrap();
+    }
+    let Some(Packet::Publish(first)) = slow.recv().await else {
+        panic!("expected PUBLISH");
+    };
+    let mut got = vec![first.payload];
+    for _ in 0..3 {
+        got.push(recv(&mut fast).await.payload);
+    }
+
+    // Queued messages of the group move to the remaining member
+    let mut unsubscribe = Unsubscribe::new();
+    unsubscribe.packet_id = 2;
+    unsubscribe.payload.push("$share/g/q".to_owned());
+    slow.send(Packet::Unsubscribe(unsubscribe)).await;
+    assert!(matches!(slow.recv().await, Some(Packet::UnsubAck(_))));
+    got.push(recv(&mut fast).await.payload);
+    got.push(recv(&mut fast).await.payload);
+    got.sort();
+    let expected: Vec<_> = (0..6).map(|i| format!("{i}")).collect();
+    assert_eq!(got, expected);
+}
+
+#[tokio::test]
+async fn no_local() {
+    let addr = "127.0.0.1:18855";
+    server(addr).await;
+
+    let mut echo = client(addr, "echo").await;
+    let mut quiet = client(addr, "quiet").await;
+    let other = client(addr, "other").await;
+    echo.subscribe("chat", QoS::AtLeastOnce).await.unwrap();
+    let mut subscribe = Subscribe::new();
+    subscribe.payload.push(Subscription {
+        topic: "chat".to_owned(),
+        qos: QoS::AtLeastOnce,
+        no_local: true,
+        ..Default::default()
+    });
+    quiet.subscribe_with(subscribe).await.unwrap();
+
+    // Own messages come back only without no local
+    echo.publish("chat", "from echo", QoS::AtLeastOnce, false)
+        .await
+        .unwrap();
+    quiet
+        .publish("chat", "from quiet", QoS::AtLeastOnce, false)
+        .await
+        .unwrap();
+    let mut got = [recv(&mut echo).await.payload, recv(&mut echo).await.payload];
+    got.sort();
+    assert_eq!(got, ["from echo", "from quiet"]);
+    assert_eq!(recv(&mut quiet).await.payload, "from echo");
+    silent(&mut quiet).await;
+
+    other
+        .publish("chat", "from other", QoS::AtLeastOnce, false)
+        .await
+        .unwrap();
+    assert_eq!(recv(&mut quiet).await.payload, "from other");
+
+    // Not allowed on a shared subscription
+    let mut subscribe = Subscribe::new();
+    subscribe.payload.push(Subscription {
+        topic: "$share/g/chat".to_owned(),
+        no_local: true,
+        ..Default::default()
+    });
+    assert!(quiet.subscribe_with(subscribe).await.is_err());
+}
+
+#[tokio::test]
+async fn sub_identifiers() {
+    let addr = "127.0.0.1:18856";
+    server(addr).await;
+
+    let mut sub = client(addr, "sub").await;
+    let pubc = client(addr, "pub").await;
+    for (filter, id) in [("i/+", 7), ("i/#", 9)] {
+        let mut props = SubscribeProperties::new();
+        props.sub_identifier = vec![id];
+        let mut subscribe = Subscribe::new();
+        subscribe.properties = Some(props);
+        subscribe.payload.push(Subscription {
+            topic: filter.to_owned(),
+            qos: QoS::AtLeastOnce,
+            ..Default::default()
+        });
+        sub.subscribe_with(subscribe).await.unwrap();
+    }
+    sub.subscribe("i/x", QoS::AtLeastOnce).await.unwrap();
+
+    // Every matching subscription's identifier, none for the one without
+    pubc.publish("i/x", "x", QoS::AtLeastOnce, false)
+        .await
+        .unwrap();
+    let publish = recv(&mut sub).await;
+    let mut ids = publish.properties.unwrap().sub_identifier;
+    ids.sort();
+    assert_eq!(ids, [7, 9]);
+}
+
+// v5 packet level client with a clean session
+async fn raw(addr: &str, client_id: &str, props: Option<ConnectProperties>) -> Raw {
+    let mut raw = Raw::connect(addr, Version::V5).await;
+    let mut connect = Connect::new();
+    connect.client_id = client_id.to_owned();
+    connect.clean_start = true;
+    connect.properties = props;
+    raw.handshake(connect).await;
+    raw
+}
+
+fn aliased(topic: &str, payload: &'static str, alias: u16) -> Publish {
+    let mut props = PublishProperties::new();
+    props.topic_alias = Some(alias);
+    let mut publish = Publish::new();
+    publish.topic_name = topic.to_owned();
+    publish.payload = payload.into();
+    publish.properties = Some(props);
+    publish
+}
+
+#[tokio::test]
+async fn topic_alias_inbound() {
+    let addr = "127.0.0.1:18858";
+    let mut server = server(addr).await;
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let hooked = seen.clone();
+    server.publish(move |publish| {
+        hooked.lock().unwrap().push(publish.topic_name.clone());
+        Ok(Packet::None)
+    });
+    let mut sub = client(addr, "sub").await;
+    sub.subscribe("long/topic", QoS::AtMostOnce).await.unwrap();
+
+    // The alias is set with the topic name, then used alone
+    let mut raw = raw(addr, "pub", None).await;
+    for (topic, payload) in [("long/topic", "set"), ("", "used")] {
+        raw.send(Packet::Publish(aliased(topic, payload, 1))).await;
+        let publish = recv(&mut sub).await;
+        assert_eq!(publish.topic_name, "long/topic");
+        assert_eq!(publish.payload, payload);
+    }
+    // Hooks see the resolved topic name
+    assert_eq!(*seen.lock().unwrap(), ["long/topic", "long/topic"]);
+}
+
+#[tokio::test]
+async fn topic_alias_invalid() {
+    let addr = "127.0.0.1:18859";
+    server(addr).await;
+
+    // Zero, above the default maximum of 32, and never set
+    for (topic, alias) in [("a", 0), ("a", 33), ("", 5)] {
+        let mut raw = raw(addr, "pub", None).await;
+        raw.send(Packet::Publish(aliased(topic, "x", alias))).await;
+        raw.closed_with(ReasonCode::TopicAliasInvalid).await;
+    }
+}
+
+#[tokio::test]
+async fn topic_alias_outbound() {
+    let addr = "127.0.0.1:18860";
+    server(addr).await;
+
+    let mut props = ConnectProperties::new();
+    props.topic_alias_max = Some(1);
+    let mut sub = raw(addr, "sub", Some(props)).await;
+    sub.subscribe("long/#", QoS::AtMostOnce).await;
+    let pubc = client(addr, "pub").await;
+    for topic in ["long/a", "long/a", "long/b"] {
+        pubc.publish(topic, "x", QoS::AtMostOnce, false)
+            .await
+            .unwrap();
+    }
+
+    let alias = |publish: &Publish| publish.properties.as_ref().and_then(|p| p.topic_alias);
+    let first = sub.recv_publish().await;
+    assert_eq!(
+        (first.topic_name.as_str(), alias(&first)),
+        ("long/a", Some(1))
+    );
+    let second = sub.recv_publish().await;
+    assert_eq!((second.topic_name.as_str(), alias(&second)), ("", Some(1)));
+    // Over the client's limit, sent with the full topic name
+    let third = sub.recv_publish().await;
+    assert_eq!((third.topic_name.as_str(), alias(&third)), ("long/b", None));
+}
+
+fn expiring(topic: &str, retain: bool, interval: u32) -> Publish {
+    let mut props = PublishProperties::new();
+    props.message_expiry_interval = Some(interval);
+    let mut publish = Publish::new();
+    publish.topic_name = topic.to_owned();
+    publish.payload = "x".into();
+    publish.qos = QoS::AtLeastOnce;
+    publish.retain = retain;
+    publish.properties = Some(props);
+    publish
+}
+
+#[tokio::test]
+async fn message_expiry() {
+    let addr = "127.0.0.1:18861";
+    server(addr).await;
+
+    let (mut sub, _) = persistent(addr, "offline").await;
+    sub.subscribe("queued/#", QoS::AtLeastOnce).await.unwrap();
+    sub.disconnect().await.unwrap();
+
+    let pubc = client(addr, "pub").await;
+    for (topic, retain) in [("queued", false), ("retained", true)] {
+        for (name, interval) in [("short", 1), ("long", 60)] {
+            let publish = expiring(&format!("{topic}/{name}"), retain, interval);
+            pubc.publish_with(publish).await.unwrap();
+        }
+    }
+    sleep(Duration::from_millis(1500)).await;
+
+    // Only what is still alive, with the remaining lifetime
+    let interval = |publish: Publish| publish.properties.and_then(|p| p.message_expiry_interval);
+    let (mut sub, _) = persistent(addr, "offline").await;
+    let publish = recv(&mut sub).await;
+    assert_eq!(publish.topic_name, "queued/long");
+    assert!(interval(publish).is_some_and(|i| i < 60));
+    silent(&mut sub).await;
+
+    sub.subscribe("retained/#", QoS::AtLeastOnce).await.unwrap();
+    let publish = recv(&mut sub).await;
+    assert_eq!(publish.topic_name, "retained/long");
+    assert!(interval(publish).is_some_and(|i| i < 60));
+    silent(&mut sub).await;
+}
+
+fn qos2(topic: &str, packet_id: u16) -> Publish {
+    let mut publish = Publish::new();
+    publish.topic_name = topic.to_owned();
+    publish.payload = "x".into();
+    publish.qos = QoS::ExactlyOnce;
+    publish.packet_id = packet_id;
+    publish
+}
+
+#[tokio::test]
+async fn receive_maximum_inbound() {
+    let addr = "127.0.0.1:18862";
+    let mut server = MqttServer::new();
+    server.receive_maximum(2);
+    serve(server, addr).await;
+
+    // Only two QoS 2 messages may wait for PUBREL
+    let mut raw = raw(addr, "eager", None).await;
+    for packet_id in 1..=3 {
+        raw.send(Packet::Publish(qos2("r", packet_id))).await;
+    }
+    for _ in 0..2 {
+        assert!(matches!(raw.recv().await, Some(Packet::PubRec(_))));
+    }
+    raw.closed_with(ReasonCode::RecvMaxExceeded).await;
+
+    // 3.1.1 clients never learn the limit, so it does not apply to them
+    let mut raw = Raw::connect(addr, Version::V311).await;
+    let mut connect = Connect::new();
+    connect.protocol_version = Version::V311;
+    connect.client_id = "old".to_owned();
+    connect.clean_start = true;
+    raw.handshake(connect).await;
+    for packet_id in 1..=3 {
+        raw.send(Packet::Publish(qos2("r", packet_id))).await;
+        assert!(matches!(raw.recv().await, Some(Packet::PubRec(_))));
+    }
+}
+
+// v5 packet level client keeping its session for a minute
+async fn raw_persistent(addr: &str, client_id: &str) -> (Raw, ConnAck) {
+    let mut props = ConnectProperties::new();
+    props.session_expiry_interval = Some(60);
+    let mut raw = Raw::connect(addr, Version::V5).await;
+    let mut connect = Connect::new();
+    connect.client_id = client_id.to_owned();
+    connect.properties = Some(props);
+    let connack = raw.handshake(connect).await;
+    (raw, connack)
+}
+
+fn pubrel(packet_id: u16) -> Packet {
+    let mut pubrel = PubRel::new();
+    pubrel.packet_id = packet_id;
+    Packet::PubRel(pubrel)
+}
+
+async fn pubcomp(raw: &mut Raw) -> PubComp {
+    match raw.recv().await {
+        Some(Packet::PubComp(pubcomp)) => pubcomp,
+        p => panic!("expected PUBCOMP, got {:?}", p),
+    }
+}
+
+#[tokio::test]
+async fn qos2_inbound() {
+    let addr = "127.0.0.1:18863";
+    server(addr).await;
+    let mut sub = client(addr, "sub").await;
+    sub.subscribe("q2", QoS::ExactlyOnce).await.unwrap();
+
+    // A retransmission before PUBREL is acknowledged but not delivered again
+    let (mut raw, _) = raw_persistent(addr, "pub").await;
+    let mut publish = qos2("q2", 7);
+    raw.send(Packet::Publish(publish.clone())).await;
+    assert!(matches!(raw.recv().await, Some(Packet::PubRec(_))));
+    publish.dup = true;
+    raw.send(Packet::Publish(publish)).await;
+    assert!(matches!(raw.recv().await, Some(Packet::PubRec(_))));
+    assert_eq!(recv(&mut sub).await.topic_name, "q2");
+    silent(&mut sub).await;
+
+    raw.send(pubrel(7)).await;
+    assert_eq!(pubcomp(&mut raw).await.reason_code, ReasonCode::Success);
+    raw.send(pubrel(9)).await;
+    let pubcomp_unknown = pubcomp(&mut raw).await;
+    assert_eq!(pubcomp_unknown.reason_code, ReasonCode::PacketIDNotFound);
+
+    // The packet id stays known across a session resume
+    raw.send(Packet::Publish(qos2("q2", 8))).await;
+    assert!(matches!(raw.recv().await, Some(Packet::PubRec(_))));
+    assert_eq!(recv(&mut sub).await.topic_name, "q2");
+    drop(raw);
+    sleep(Duration::from_millis(100)).await;
+
+    let (mut raw, connack) = raw_persistent(addr, "pub").await;
+    assert!(connack.session_present);
+    let mut publish = qos2("q2", 8);
+    publish.dup = true;
+    raw.send(Packet::Publish(publish)).await;
+    assert!(matches!(raw.recv().await, Some(Packet::PubRec(_))));
+    silent(&mut sub).await;
+    raw.send(pubrel(8)).await;
+    assert_eq!(pubcomp(&mut raw).await.reason_code, ReasonCode::Success);
+}
+
+#[tokio::test]
+async fn qos_resend() {
+    let addr = "127.0.0.1:18864";
+    server(addr).await;
+    let (mut sub, _) = raw_persistent(addr, "sub").await;
+    sub.subscribe("out", QoS::ExactlyOnce).await;
+
+    let pubc = client(addr, "pub").await;
+    for (payload, qos) in [
+        ("one", QoS::AtLeastOnce),
+        ("two", QoS::ExactlyOnce),
+        ("three", QoS::ExactlyOnce),
+    ] {
+        pubc.publish("out", payload, qos, false).await.unwrap();
+    }
+    let mut sent = Vec::new();
+    for _ in 0..3 {
+        sent.push(sub.recv_publish().await);
+    }
+    // Only the last one gets as far as PUBREC
+    let mut pubrec = PubRec::new();
+    pubrec.packet_id = sent[2].packet_id;
+    sub.send(Packet::PubRec(pubrec)).await;
+    assert!(matches!(sub.recv().await, Some(Packet::PubRel(_))));
+    drop(sub);
+    sleep(Duration::from_millis(100)).await;
+
+    // Unacknowledged messages come again with DUP and the same packet ids
+    let (mut sub, connack) = raw_persistent(addr, "sub").await;
+    assert!(connack.session_present);
+    for publish in &sent[..2] {
+        let resent = sub.recv_publish().await;
+        assert!(resent.dup);
+        assert_eq!(resent.packet_id, publish.packet_id);
+        assert_eq!(resent.payload, publish.payload);
+    }
+    match sub.recv().await {
+        Some(Packet::PubRel(pubrel)) => assert_eq!(pubrel.packet_id, sent[2].packet_id),
+        p => panic!("expected PUBREL, got {:?}", p),
+    }
+
+    // PUBREC for a packet id the broker never sent
+    let mut pubrec = PubRec::new();
+    pubrec.packet_id = 999;
+    sub.send(Packet::PubRec(pubrec)).await;
+    match sub.recv().await {
+        Some(Packet::PubRel(pubrel)) => {
+            assert_eq!(pubrel.reason_code, ReasonCode::PacketIDNotFound)
+        }
+        p => panic!("expected PUBREL, got {:?}", p),
+    }
+}
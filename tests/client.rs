@@ -0,0 +1,189 @@
+// Client flows against the broker on a local port
+
+mod common;
+
+use common::*;
+use rsmqtt::*;
+use std::time::Duration;
+use tokio::time::{sleep, timeout};
+
+#[tokio::test]
+async fn publish_subscribe() {
+    let addr = "127.0.0.1:18831";
+    server(addr).await;
+
+    let mut sub = client(addr, "sub").await;
+    let pubc = client(addr, "pub").await;
+    let reason_code = sub.subscribe("a/#", QoS::ExactlyOnce).await.unwrap();
+    assert_eq!(reason_code, ReasonCode::GrantedQoS2);
+
+    for qos in [QoS::AtMostOnce, QoS::AtLeastOnce, QoS::ExactlyOnce] {
+        pubc.publish("a/b", format!("{:?}", qos), qos, false)
+            .await
+            .unwrap();
+        let publish = timeout(Duration::from_secs(5), sub.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(publish.topic_name, "a/b");
+        assert_eq!(publish.qos, qos);
+        assert_eq!(publish.payload, format!("{:?}", qos));
+    }
+
+    let reason_code = sub.unsubscribe("a/#").await.unwrap();
+    assert_eq!(reason_code, ReasonCode::Success);
+    sub.disconnect().await.unwrap();
+    assert!(sub
+        .publish("a/b", "x", QoS::AtMostOnce, false)
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn keepalive_pings() {
+    let addr = "127.0.0.1:18832";
+    server(addr).await;
+
+    let mut client = MqttClient::new();
+    client.client_id("idle").keepalive(1);
+    client.connect(addr).await.unwrap();
+    // The broker drops links silent for 1.5 keep alive periods
+    sleep(Duration::from_secs(3)).await;
+    client
+        .publish("idle", "still here", QoS::AtLeastOnce, false)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn reconnect_resubscribes() {
+    let addr = "127.0.0.1:18833";
+    let server = server(addr).await;
+
+    let mut sub = MqttClient::new();
+    sub.client_id("flaky")
+        .reconnect(Duration::from_millis(50), Duration::from_secs(1));
+    let mut events = sub.events().unwrap();
+    sub.connect(addr).await.unwrap();
+    sub.subscribe("r", QoS::AtLeastOnce).await.unwrap();
+    assert!(matches!(events.recv().await, Some(Event::Connected(_))));
+
+    // Session expiry 0, so the broker forgets the subscription
+    assert!(server.clients().kick("flaky", ReasonCode::AdminAction));
+    assert!(matches!(events.recv().await, Some(Event::Disconnected(_))));
+    assert!(matches!(events.recv().await, Some(Event::Reconnecting(_))));
+    match events.recv().await {
+        Some(Event::Connected(connack)) => assert!(!connack.session_present),
+        e => panic!("{:?}", e),
+    }
+
+    let pubc = client(addr, "pub2").await;
+    // Give the replayed SUBSCRIBE a moment
+    sleep(Duration::from_millis(100)).await;
+    pubc.publish("r", "again", QoS::AtLeastOnce, false)
+        .await
+        .unwrap();
+    let publish = timeout(Duration::from_secs(5), sub.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(publish.payload, "again");
+}
+
+#[tokio::test]
+async fn reconnect_keeps_clean_session() {
+    let addr = "127.0.0.1:18835";
+    let server = server(addr).await;
+
+    let mut client = MqttClient::new();
+    client
+        .version(Version::V311)
+        .client_id("clean")
+        .reconnect(Duration::from_millis(50), Duration::from_secs(1));
+    let mut events = client.events().unwrap();
+    client.connect(addr).await.unwrap();
+    assert!(matches!(events.recv().await, Some(Event::Connected(_))));
+
+    // Every reconnect asks for a clean session again, so none is ever kept
+    for _ in 0..2 {
+        assert!(server.clients().kick("clean", ReasonCode::AdminAction));
+        assert!(matches!(events.recv().await, Some(Event::Disconnected(_))));
+        assert!(matches!(events.recv().await, Some(Event::Reconnecting(_))));
+        match events.recv().await {
+            Some(Event::Connected(connack)) => assert!(!connack.session_present),
+            e => panic!("{:?}", e),
+        }
+    }
+}
+
+#[tokio::test]
+async fn reconnect_retransmits() {
+    use bytes::BytesMut;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    // Broker that drops the first connection before acknowledging the publish
+    let listener = TcpListener::bind("127.0.0.1:18834").await.unwrap();
+    let broker = tokio::spawn(async move {
+        let mut publishes = Vec::new();
+        for session_present in [false, true] {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut codec = MqttCodec::default();
+            let mut buf = BytesMut::new();
+            let mut next = async |stream: &mut tokio::net::TcpStream, buf: &mut BytesMut| loop {
+                if let Some(packet) = codec.decode(buf).unwrap() {
+                    return packet;
+                }
+                stream.read_buf(buf).await.unwrap();
+            };
+            let Packet::Connect(connect) = next(&mut stream, &mut buf).await else {
+                panic!("expected CONNECT");
+            };
+            assert!(!connect.clean_start);
+            let mut connack = ConnAck::new();
+            connack.session_present = session_present;
+            let mut out = BytesMut::new();
+            MqttCodec::default()
+                .encode(Packet::ConnAck(connack), &mut out)
+                .unwrap();
+            stream.write_all(&out).await.unwrap();
+
+            let Packet::Publish(publish) = next(&mut stream, &mut buf).await else {
+                panic!("expected PUBLISH");
+            };
+            publishes.push(publish.clone());
+            if session_present {
+                let mut puback = PubAck::new();
+                puback.packet_id = publish.packet_id;
+                let mut out = BytesMut::new();
+                MqttCodec::default()
+                    .encode(Packet::PubAck(puback), &mut out)
+                    .unwrap();
+                stream.write_all(&out).await.unwrap();
+                sleep(Duration::from_millis(100)).await;
+            }
+        }
+        publishes
+    });
+
+    let mut client = MqttClient::new();
+    client
+        .client_id("resend")
+        .clean_start(false)
+        .reconnect(Duration::from_millis(10), Duration::from_millis(100));
+    client.connect("127.0.0.1:18834").await.unwrap();
+    timeout(
+        Duration::from_secs(5),
+        client.publish("t", "once", QoS::AtLeastOnce, false),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+
+    let publishes = broker.await.unwrap();
+    assert!(!publishes[0].dup);
+    assert!(publishes[1].dup);
+    assert_eq!(publishes[0].packet_id, publishes[1].packet_id);
+    assert_eq!(publishes[1].payload, "once");
+}
@@ -0,0 +1,272 @@
+// Round trips and version rules of MqttCodec
+
+use bytes::{Bytes, BytesMut};
+use rsmqtt::*;
+use tokio_util::codec::{Decoder, Encoder};
+
+fn connect(version: Version) -> BytesMut {
+    let mut connect = Connect::new();
+    connect.protocol_version = version;
+    connect.client_id = "client".to_owned();
+    connect.clean_start = true;
+    let mut buf = BytesMut::new();
+    MqttCodec::new(version, u32::MAX)
+        .encode(Packet::Connect(connect), &mut buf)
+        .unwrap();
+    buf
+}
+
+#[test]
+fn version_from_first_connect() {
+    let mut codec = MqttCodec::default();
+    let mut buf = connect(Version::V311);
+    assert!(matches!(
+        codec.decode(&mut buf),
+        Ok(Some(Packet::Connect(_)))
+    ));
+    assert_eq!(codec.version, Version::V311);
+
+    // A second CONNECT is still decoded, but keeps the negotiated version
+    let mut buf = connect(Version::V5);
+    match codec.decode(&mut buf) {
+        Ok(Some(Packet::Connect(connect))) => assert_eq!(connect.protocol_version, Version::V5),
+        p => panic!("{:?}", p),
+    }
+    assert_eq!(codec.version, Version::V311);
+}
+
+#[test]
+fn subscribe_reserved_options() {
+    // No local set on the only subscription
+    let subscribe = [0x82, 6, 0, 1, 0, 1, b'a', 0x04];
+    for version in [Version::V31, Version::V311] {
+        let mut codec = MqttCodec::new(version, u32::MAX);
+        let e = codec
+            .decode(&mut BytesMut::from(&subscribe[..]))
+            .unwrap_err();
+        assert_eq!(e.reason_code(), Some(ReasonCode::MalformedPacket));
+    }
+
+    let subscribe = [0x82, 7, 0, 1, 0, 0, 1, b'a', 0x04];
+    let mut codec = MqttCodec::new(Version::V5, u32::MAX);
+    match codec.decode(&mut BytesMut::from(&subscribe[..])) {
+        Ok(Some(Packet::Subscribe(subscribe))) => assert!(subscribe.payload[0].no_local),
+        p => panic!("{:?}", p),
+    }
+}
+
+// Every packet type with every field the version can carry
+fn packets(version: Version) -> Vec<Packet> {
+    let v5 = version == Version::V5;
+    let user_property = vec![("k".to_owned(), "v".to_owned())];
+
+    let mut connect = Connect::new();
+    connect.protocol_name = match version {
+        Version::V31 => "MQIsdp".to_owned(),
+        _ => "MQTT".to_owned(),
+    };
+    connect.protocol_version = version;
+    connect.keepalive = 30;
+    connect.client_id = "client".to_owned();
+    connect.will_flag = true;
+    connect.will_qos = QoS::AtLeastOnce;
+    connect.will_retain = true;
+    connect.will_topic = "will".to_owned();
+    connect.will_payload = Bytes::from_static(b"bye");
+    connect.username_flag = true;
+    connect.username = "user".to_owned();
+    connect.password_flag = true;
+    connect.password = Bytes::from_static(b"secret");
+    if v5 {
+        let mut props = ConnectProperties::new();
+        props.session_expiry_interval = Some(60);
+        props.receive_maximum = Some(10);
+        props.max_packet_size = Some(1024);
+        props.topic_alias_max = Some(4);
+        props.request_response_info = Some(1);
+        props.request_problem_info = Some(0);
+        props.user_property = user_property.clone();
+        props.auth_method = Some("token".to_owned());
+        props.auth_data = Some(Bytes::from_static(&[1, 2]));
+        connect.properties = Some(props);
+        let mut props = WillProperties::new();
+        props.will_delay_interval = Some(5);
+        props.payload_format_indicator = Some(1);
+        props.message_expiry_interval = Some(30);
+        props.content_type = Some("text/plain".to_owned());
+        props.response_topic = Some("reply".to_owned());
+        props.correlation_data = Some(Bytes::from_static(&[3]));
+        props.user_property = user_property.clone();
+        connect.will_properties = Some(props);
+    }
+
+    let mut connack = ConnAck::new();
+    // 3.1 has no session present flag
+    connack.session_present = version != Version::V31;
+    if v5 {
+        let mut props = ConnAckProperties::new();
+        props.session_expiry_interval = Some(60);
+        props.receive_maximum = Some(10);
+        props.maximum_qos = Some(1);
+        props.retain_available = Some(1);
+        props.max_packet_size = Some(1024);
+        props.assigned_client_identifier = Some("id".to_owned());
+        props.topic_alias_max = Some(4);
+        props.reason_string = Some("reason".to_owned());
+        props.user_property = user_property.clone();
+        props.wildcard_sub_available = Some(1);
+        props.sub_identifier_available = Some(1);
+        props.shared_sub_available = Some(1);
+        props.server_keep_alive = Some(20);
+        props.response_info = Some("info".to_owned());
+        props.server_reference = Some("other".to_owned());
+        props.auth_method = Some("token".to_owned());
+        props.auth_data = Some(Bytes::from_static(&[1]));
+        connack.properties = Some(props);
+    }
+
+    let mut publish = Publish::new();
+    publish.dup = true;
+    publish.qos = QoS::ExactlyOnce;
+    publish.retain = true;
+    publish.topic_name = "a/b".to_owned();
+    publish.packet_id = 7;
+    publish.payload = Bytes::from_static(&[0, 0xFF, 0x80]);
+    if v5 {
+        let mut props = PublishProperties::new();
+        props.payload_format_indicator = Some(0);
+        props.message_expiry_interval = Some(30);
+        props.content_type = Some("bin".to_owned());
+        props.response_topic = Some("reply".to_owned());
+        props.correlation_data = Some(Bytes::from_static(&[9]));
+        props.sub_identifier = vec![1, 268_435_455];
+        props.topic_alias = Some(2);
+        props.user_property = user_property.clone();
+        publish.properties = Some(props);
+    }
+
+    let mut puback = PubAck::new();
+    puback.packet_id = 7;
+    let mut pubrec = PubRec::new();
+    pubrec.packet_id = 7;
+    let mut pubrel = PubRel::new();
+    pubrel.packet_id = 7;
+    let mut pubcomp = PubComp::new();
+    pubcomp.packet_id = 7;
+    if v5 {
+        puback.reason_code = ReasonCode::NotMatchingSubscribers;
+        let mut props = PubAckProperties::new();
+        props.reason_string = Some("none".to_owned());
+        props.user_property = user_property.clone();
+        puback.properties = Some(props);
+        pubrec.reason_code = ReasonCode::QuotaExceeded;
+        let mut props = PubRecProperties::new();
+        props.reason_string = Some("full".to_owned());
+        pubrec.properties = Some(props);
+        pubrel.reason_code = ReasonCode::PacketIDNotFound;
+        let mut props = PubRelProperties::new();
+        props.user_property = user_property.clone();
+        pubrel.properties = Some(props);
+        pubcomp.reason_code = ReasonCode::PacketIDNotFound;
+        let mut props = PubCompProperties::new();
+        props.reason_string = Some("gone".to_owned());
+        pubcomp.properties = Some(props);
+    }
+
+    let mut subscribe = Subscribe::new();
+    subscribe.packet_id = 8;
+    subscribe.payload.push(Subscription {
+        topic: "a/+".to_owned(),
+        qos: QoS::AtLeastOnce,
+        ..Default::default()
+    });
+    subscribe.payload.push(Subscription {
+        topic: "$share/g/#".to_owned(),
+        qos: QoS::ExactlyOnce,
+        no_local: v5,
+        retain_as_published: v5,
+        retain_handling: match v5 {
+            true => RetainHandling::Never,
+            false => RetainHandling::Sub,
+        },
+    });
+    let mut suback = SubAck::new();
+    suback.packet_id = 8;
+    suback.payload = vec![ReasonCode::GrantedQoS1, ReasonCode::UnspecifiedError];
+    let mut unsubscribe = Unsubscribe::new();
+    unsubscribe.packet_id = 9;
+    unsubscribe.payload = vec!["a/+".to_owned(), "$share/g/#".to_owned()];
+    let mut unsuback = UnsubAck::new();
+    unsuback.packet_id = 9;
+    let mut disconnect = Disconnect::new();
+    if v5 {
+        let mut props = SubscribeProperties::new();
+        props.sub_identifier = vec![3];
+        props.user_property = user_property.clone();
+        subscribe.properties = Some(props);
+        let mut props = SubAckProperties::new();
+        props.reason_string = Some("partly".to_owned());
+        suback.properties = Some(props);
+        suback.payload.push(ReasonCode::SharedSubNotSupported);
+        let mut props = UnsubscribeProperties::new();
+        props.user_property = user_property.clone();
+        unsubscribe.properties = Some(props);
+        let mut props = UnsubAckProperties::new();
+        props.reason_string = Some("partly".to_owned());
+        unsuback.properties = Some(props);
+        unsuback.payload = vec![ReasonCode::Success, ReasonCode::NoSubscriptionExisted];
+        disconnect.reason_code = ReasonCode::DisconnectWithWillMessage;
+        let mut props = DisconnectProperties::new();
+        props.session_expiry_interval = Some(0);
+        props.reason_string = Some("bye".to_owned());
+        props.server_reference = Some("other".to_owned());
+        props.user_property = user_property.clone();
+        disconnect.properties = Some(props);
+    }
+
+    let mut packets = vec![
+        Packet::Connect(connect),
+        Packet::ConnAck(connack),
+        Packet::Publish(publish),
+        Packet::PubAck(puback),
+        Packet::PubRec(pubrec),
+        Packet::PubRel(pubrel),
+        Packet::PubComp(pubcomp),
+        Packet::Subscribe(subscribe),
+        Packet::SubAck(suback),
+        Packet::Unsubscribe(unsubscribe),
+        Packet::UnsubAck(unsuback),
+        Packet::PingReq,
+        Packet::PingResp,
+        Packet::Disconnect(disconnect),
+    ];
+    // AUTH is new in v5
+    if v5 {
+        let mut auth = Auth::new();
+        auth.reason_code = ReasonCode::ContinueAuthentication;
+        let mut props = AuthProperties::new();
+        props.auth_method = Some("token".to_owned());
+        props.auth_data = Some(Bytes::from_static(&[0xFF]));
+        props.reason_string = Some("again".to_owned());
+        props.user_property = user_property;
+        auth.properties = Some(props);
+        packets.push(Packet::Auth(auth));
+    }
+    packets
+}
+
+#[test]
+fn round_trip() {
+    for version in [Version::V31, Version::V311, Version::V5] {
+        for packet in packets(version) {
+            let expected = format!("{packet:?}");
+            let mut codec = MqttCodec::new(version, u32::MAX);
+            let mut buf = BytesMut::new();
+            codec.encode(packet, &mut buf).unwrap();
+            let decoded = codec.decode(&mut buf).unwrap().unwrap();
+            // Every field compared through its Debug form
+            assert_eq!(format!("{decoded:?}"), expected, "{version:?}");
+            assert!(buf.is_empty());
+        }
+    }
+}
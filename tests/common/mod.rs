@@ -0,0 +1,127 @@
+// Broker and client helpers shared by the integration tests
+#![allow(dead_code)]
+
+use bytes::BytesMut;
+use rsmqtt::*;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout};
+use tokio_util::codec::{Decoder, Encoder};
+
+pub async fn server(addr: &str) -> MqttServer {
+    serve(MqttServer::new(), addr).await
+}
+
+// Runs a server the test configured first
+pub async fn serve(mut server: MqttServer, addr: &str) -> MqttServer {
+    server.tcp(addr).run().await.unwrap();
+    // Let the listener bind
+    sleep(Duration::from_millis(100)).await;
+    server
+}
+
+pub async fn client(addr: &str, client_id: &str) -> MqttClient {
+    let mut client = MqttClient::new();
+    client.client_id(client_id);
+    let connack = client.connect(addr).await.unwrap();
+    assert_eq!(connack.reason_code, ReasonCode::Success);
+    client
+}
+
+// Next message, failing the test after a few seconds
+pub async fn recv(client: &mut MqttClient) -> Publish {
+    timeout(Duration::from_secs(5), client.recv())
+        .await
+        .expect("no message")
+        .expect("connection closed")
+}
+
+// Nothing arrives for a while
+pub async fn silent(client: &mut MqttClient) {
+    if let Ok(publish) = timeout(Duration::from_millis(300), client.recv()).await {
+        panic!("unexpected message {:?}", publish);
+    }
+}
+
+// Packet level connection for flows the client does not expose
+pub struct Raw {
+    stream: TcpStream,
+    codec: MqttCodec,
+    buf: BytesMut,
+}
+
+impl Raw {
+    pub async fn connect(addr: &str, version: Version) -> Self {
+        Self {
+            stream: TcpStream::connect(addr).await.unwrap(),
+            codec: MqttCodec::new(version, u32::MAX),
+            buf: BytesMut::new(),
+        }
+    }
+
+    pub async fn send(&mut self, packet: Packet) {
+        let mut out = BytesMut::new();
+        self.codec.encode(packet, &mut out).unwrap();
+        self.stream.write_all(&out).await.unwrap();
+    }
+
+    // Sends CONNECT and waits for the CONNACK
+    pub async fn handshake(&mut self, connect: Connect) -> ConnAck {
+        self.send(Packet::Connect(connect)).await;
+        match self.recv().await {
+            Some(Packet::ConnAck(connack)) => connack,
+            p => panic!("expected CONNACK, got {:?}", p),
+        }
+    }
+
+    pub async fn subscribe(&mut self, filter: &str, qos: QoS) {
+        let mut subscribe = Subscribe::new();
+        subscribe.packet_id = 1;
+        subscribe.payload.push(Subscription {
+            topic: filter.to_owned(),
+            qos,
+            ..Default::default()
+        });
+        self.send(Packet::Subscribe(subscribe)).await;
+        assert!(matches!(self.recv().await, Some(Packet::SubAck(_))));
+    }
+
+    pub async fn send_bytes(&mut self, data: &[u8]) {
+        self.stream.write_all(data).await.unwrap();
+    }
+
+    // Next packet, None once the server closes the connection
+    pub async fn recv(&mut self) -> Option<Packet> {
+        timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(packet) = self.codec.decode(&mut self.buf).unwrap() {
+                    return Some(packet);
+                }
+                if self.stream.read_buf(&mut self.buf).await.unwrap_or(0) == 0 {
+                    return None;
+                }
+            }
+        })
+        .await
+        .expect("no packet")
+    }
+
+    pub async fn recv_publish(&mut self) -> Publish {
+        match self.recv().await {
+            Some(Packet::Publish(publish)) => publish,
+            p => panic!("expected PUBLISH, got {:?}", p),
+        }
+    }
+
+    // Expects a DISCONNECT with the reason code, then the close
+    pub async fn closed_with(&mut self, reason_code: ReasonCode) {
+        match self.recv().await {
+            Some(Packet::Disconnect(disconnect)) => {
+                assert_eq!(disconnect.reason_code, reason_code)
+            }
+            p => panic!("expected DISCONNECT, got {:?}", p),
+        }
+        assert!(self.recv().await.is_none());
+    }
+}
@@ -0,0 +1,216 @@
+// Decoding arbitrary input must fail with an error, never panic, and binary
+// fields come back exactly as sent
+
+use bytes::{Bytes, BytesMut};
+use proptest::prelude::*;
+use rsmqtt::*;
+use tokio_util::codec::{Decoder, Encoder};
+
+const VERSIONS: [Version; 3] = [Version::V31, Version::V311, Version::V5];
+
+fn decode_all(version: Version, data: &[u8]) {
+    let mut codec = MqttCodec::new(version, u32::MAX);
+    let mut buf = BytesMut::from(data);
+    while let Ok(Some(_)) = codec.decode(&mut buf) {}
+}
+
+// Remaining length in one to four bytes
+fn length(mut len: usize) -> Vec<u8> {
+    let mut out = Vec::new();
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        out.push(byte);
+        if len == 0 {
+            return out;
+        }
+    }
+}
+
+// Valid packets of every type, used as seeds for mutation
+fn corpus(version: Version) -> Vec<Vec<u8>> {
+    let mut connect = Connect::new();
+    connect.protocol_version = version;
+    connect.client_id = "client".to_owned();
+    connect.clean_start = true;
+    connect.will_flag = true;
+    connect.will_topic = "will".to_owned();
+    connect.will_payload = Bytes::from_static(b"bye");
+    connect.username_flag = true;
+    connect.username = "user".to_owned();
+    let mut props = ConnectProperties::new();
+    props.session_expiry_interval = Some(60);
+    props.user_property.push(("k".to_owned(), "v".to_owned()));
+    props.auth_data = Some(Bytes::from_static(&[1, 2, 3]));
+    connect.properties = Some(props);
+    let mut props = WillProperties::new();
+    props.will_delay_interval = Some(5);
+    props.correlation_data = Some(Bytes::from_static(&[4, 5]));
+    connect.will_properties = Some(props);
+
+    let mut connack = ConnAck::new();
+    let mut props = ConnAckProperties::new();
+    props.receive_maximum = Some(10);
+    props.assigned_client_identifier = Some("id".to_owned());
+    connack.properties = Some(props);
+
+    let mut publish = Publish::new();
+    publish.topic_name = "a/b".to_owned();
+    publish.qos = QoS::ExactlyOnce;
+    publish.packet_id = 7;
+    publish.payload = Bytes::from_static(b"payload");
+    let mut props = PublishProperties::new();
+    props.message_expiry_interval = Some(30);
+    props.sub_identifier.push(268_435_455);
+    props.topic_alias = Some(1);
+    publish.properties = Some(props);
+
+    let mut puback = PubAck::new();
+    puback.packet_id = 7;
+    puback.reason_code = ReasonCode::NotMatchingSubscribers;
+    let mut pubrec = PubRec::new();
+    pubrec.packet_id = 7;
+    let mut pubrel = PubRel::new();
+    pubrel.packet_id = 7;
+    let mut pubcomp = PubComp::new();
+    pubcomp.packet_id = 7;
+
+    let mut subscribe = Subscribe::new();
+    subscribe.packet_id = 8;
+    subscribe.payload.push(Subscription {
+        topic: "a/+/#".to_owned(),
+        qos: QoS::AtLeastOnce,
+        no_local: true,
+        retain_as_published: true,
+        retain_handling: RetainHandling::NewSub,
+    });
+    let mut props = SubscribeProperties::new();
+    props.sub_identifier.push(3);
+    subscribe.properties = Some(props);
+
+    let mut suback = SubAck::new();
+    suback.packet_id = 8;
+    suback.payload = vec![ReasonCode::GrantedQoS1, ReasonCode::UnspecifiedError];
+
+    let mut unsubscribe = Unsubscribe::new();
+    unsubscribe.packet_id = 9;
+    unsubscribe.payload.push("a/+/#".to_owned());
+
+    let mut unsuback = UnsubAck::new();
+    unsuback.packet_id = 9;
+    unsuback.payload = vec![ReasonCode::Success];
+
+    let mut disconnect = Disconnect::new();
+    let mut props = DisconnectProperties::new();
+    props.session_expiry_interval = Some(0);
+    disconnect.properties = Some(props);
+
+    let packets = vec![
+        Packet::Connect(connect),
+        Packet::ConnAck(connack),
+        Packet::Publish(publish),
+        Packet::PubAck(puback),
+        Packet::PubRec(pubrec),
+        Packet::PubRel(pubrel),
+        Packet::PubComp(pubcomp),
+        Packet::Subscribe(subscribe),
+        Packet::SubAck(suback),
+        Packet::Unsubscribe(unsubscribe),
+        Packet::UnsubAck(unsuback),
+        Packet::PingReq,
+        Packet::PingResp,
+        Packet::Disconnect(disconnect),
+        Packet::Auth(Auth::new()),
+    ];
+    packets
+        .into_iter()
+        .map(|packet| {
+            let mut codec = MqttCodec::new(version, u32::MAX);
+            let mut buf = BytesMut::new();
+            codec.encode(packet, &mut buf).unwrap();
+            buf.to_vec()
+        })
+        .collect()
+}
+
+#[test]
+fn corpus_decodes() {
+    for version in VERSIONS {
+        for packet in corpus(version) {
+            let mut codec = MqttCodec::new(version, u32::MAX);
+            let mut buf = BytesMut::from(&packet[..]);
+            assert!(codec.decode(&mut buf).unwrap().is_some());
+            assert!(buf.is_empty());
+        }
+    }
+}
+
+#[test]
+fn binary_connect_fields() {
+    // Not valid UTF-8
+    let will_payload = Bytes::from_static(&[0xFF, 0x00, 0xC3, 0x28]);
+    let password = Bytes::from_static(&[0x80, 0xFE, 0x00]);
+    for version in VERSIONS {
+        let mut connect = Connect::new();
+        connect.protocol_version = version;
+        connect.client_id = "client".to_owned();
+        connect.will_flag = true;
+        connect.will_topic = "will".to_owned();
+        connect.will_payload = will_payload.clone();
+        connect.username_flag = true;
+        connect.username = "user".to_owned();
+        connect.password_flag = true;
+        connect.password = password.clone();
+
+        let mut codec = MqttCodec::new(version, u32::MAX);
+        let mut buf = BytesMut::new();
+        codec.encode(Packet::Connect(connect), &mut buf).unwrap();
+        let Some(Packet::Connect(connect)) = codec.decode(&mut buf).unwrap() else {
+            panic!("expected CONNECT");
+        };
+        assert_eq!(connect.will_payload, will_payload);
+        assert_eq!(connect.password, password);
+    }
+}
+
+proptest! {
+    #[test]
+    fn arbitrary_bytes(data in proptest::collection::vec(any::<u8>(), 0..512)) {
+        for version in VERSIONS {
+            decode_all(version, &data);
+        }
+    }
+
+    #[test]
+    fn arbitrary_body(byte1 in any::<u8>(), body in proptest::collection::vec(any::<u8>(), 0..256)) {
+        let mut data = vec![byte1];
+        data.extend(length(body.len()));
+        data.extend(body);
+        for version in VERSIONS {
+            decode_all(version, &data);
+        }
+    }
+
+    #[test]
+    fn mutated_corpus(
+        seed in any::<prop::sample::Index>(),
+        edits in proptest::collection::vec((any::<prop::sample::Index>(), any::<u8>()), 1..8),
+        cut in any::<prop::sample::Index>(),
+    ) {
+        for version in VERSIONS {
+            let corpus = corpus(version);
+            let mut packet = corpus[seed.index(corpus.len())].clone();
+            for (i, byte) in &edits {
+                let i = i.index(packet.len());
+                packet[i] = *byte;
+            }
+            decode_all(version, &packet);
+            // Truncated body with the original remaining length
+            packet.truncate(cut.index(packet.len() + 1));
+            decode_all(version, &packet);
+        }
+    }
+}
@@ -0,0 +1,94 @@
+// Shared property decoding against the per-packet allowed sets
+
+use bytes::{Bytes, BytesMut};
+use rsmqtt::*;
+
+// Property length followed by the raw properties
+fn block(props: &[u8]) -> Bytes {
+    let mut data = vec![props.len() as u8];
+    data.extend_from_slice(props);
+    Bytes::from(data)
+}
+
+fn reason_code<T>(r: Result<T, impl Into<Error>>) -> Option<ReasonCode> {
+    r.err().and_then(|e| e.into().reason_code())
+}
+
+#[test]
+fn round_trip() {
+    let mut props = Properties::new();
+    props.push(
+        Property::MessageExpiryInterval,
+        PropertyValue::FourByteInteger(30),
+    );
+    props.push(
+        Property::SubIdentifier,
+        PropertyValue::VariableByteInteger(200),
+    );
+    props.push(
+        Property::SubIdentifier,
+        PropertyValue::VariableByteInteger(1),
+    );
+    props.push(
+        Property::CorrelationData,
+        PropertyValue::Binary(Bytes::from_static(b"id")),
+    );
+    props.set_user_property(vec![("k".to_owned(), "v".to_owned())]);
+
+    let mut buf = BytesMut::new();
+    props.pack(&mut buf).unwrap();
+    assert_eq!(buf.len(), props.len());
+
+    let mut read = block(&buf);
+    let decoded = Properties::unpack(&mut read, PUBLISH_PROPERTIES)
+        .unwrap()
+        .unwrap();
+    assert_eq!(decoded, props);
+    assert_eq!(decoded.get_u32(Property::MessageExpiryInterval), Some(30));
+    assert_eq!(decoded.sub_identifier(), vec![200, 1]);
+    assert_eq!(decoded.user_property(), vec![("k", "v")]);
+    assert_eq!(decoded.get(Property::ContentType), None);
+}
+
+#[test]
+fn rejects_duplicate() {
+    // Topic alias twice
+    let mut read = block(&[0x23, 0x00, 0x01, 0x23, 0x00, 0x02]);
+    let r = Properties::unpack(&mut read, PUBLISH_PROPERTIES);
+    assert_eq!(reason_code(r), Some(ReasonCode::ProtocolError));
+
+    // User properties may repeat
+    let mut read = block(&[0x26, 0, 1, b'a', 0, 0, 0x26, 0, 1, b'a', 0, 0]);
+    let props = Properties::unpack(&mut read, ACK_PROPERTIES)
+        .unwrap()
+        .unwrap();
+    assert_eq!(props.user_property().len(), 2);
+}
+
+#[test]
+fn rejects_disallowed() {
+    // Topic alias in a PUBACK
+    let mut read = block(&[0x23, 0x00, 0x01]);
+    let r = Properties::unpack(&mut read, ACK_PROPERTIES);
+    assert_eq!(reason_code(r), Some(ReasonCode::ProtocolError));
+
+    // Subscription identifier in an UNSUBSCRIBE
+    let mut read = block(&[0x0B, 0x01]);
+    let r = UnsubscribeProperties::unpack(&mut read);
+    assert_eq!(reason_code(r), Some(ReasonCode::ProtocolError));
+}
+
+#[test]
+fn packet_properties() {
+    let mut props = DisconnectProperties::new();
+    props.reason_string = Some("bye".to_owned());
+    let mut disconnect = Disconnect::new();
+    disconnect.properties = Some(props);
+
+    let props = Packet::Disconnect(disconnect).properties().unwrap();
+    assert_eq!(
+        props.get(Property::ReasonString),
+        Some(&PropertyValue::String("bye".to_owned()))
+    );
+    assert!(Packet::PingReq.properties().is_none());
+}